@@ -60,6 +60,9 @@
           source = noCtx (toString v.source);
           recursive = v.recursive;
         }
+        // lib.optionalAttrs (v.onChange != "") {
+          onChange = noCtx v.onChange;
+        }
         // (
           if isFromRepo v.source
           then {
@@ -74,6 +77,24 @@
     hmFiles;
 
   mkHmMappingJson = user: pkgs.writeText "hm-mapping-${user}.json" (builtins.toJSON (mkHmMapping user));
+
+  # System-wide defaults, layered under each user's own config file.
+  tomlFormat = pkgs.formats.toml {};
+  configToml = tomlFormat.generate "nix-file-overlay-config.toml" (
+    lib.recursiveUpdate (lib.optionalAttrs (cfg.systemRepoPath != null) {
+      repos.system = cfg.systemRepoPath;
+    })
+    cfg.settings
+    // {
+      policies = cfg.policies ++ (cfg.settings.policies or []);
+    }
+  );
+
+  # Lets the tool tell files from this repo apart from files that come from
+  # other flake inputs (which are also `-source` store paths).
+  metaJson = pkgs.writeText "nix-file-overlay-meta.json" (builtins.toJSON {
+    selfSource = noCtx selfPrefix;
+  });
 in {
   options.dreaming.nixFileOverlay = {
     enable = lib.mkEnableOption "nix-file-overlay system service for temporary file overrides";
@@ -83,6 +104,46 @@ in {
       default = null;
       description = ''
         Path to the system NixOS configuration repository.
+        Written to the system config as `repos.system`.
+      '';
+    };
+
+    settings = lib.mkOption {
+      type = tomlFormat.type;
+      default = {};
+      description = ''
+        Contents of `/etc/nix-file-overlay/config.toml`, the system-wide
+        config that users' own config files override.
+      '';
+    };
+
+    policies = lib.mkOption {
+      type = lib.types.listOf (lib.types.attrsOf lib.types.str);
+      default = [
+        {
+          path = "/etc/sudoers";
+          action = "deny";
+          reason = "a broken sudoers locks out sudo, edit it in the config instead";
+        }
+        {
+          path = "/etc/sudoers.d/*";
+          action = "deny";
+          reason = "a broken sudoers locks out sudo, edit it in the config instead";
+        }
+        {
+          path = "/etc/pam.d/*";
+          action = "try";
+        }
+        {
+          path = "/etc/ssh/sshd_config";
+          action = "try";
+        }
+      ];
+      description = ''
+        Rules for overlays of matching paths, with `path` (a glob), `action`
+        (deny, confirm, try, persistent or max-ttl), and optionally `max_ttl`
+        and `reason`. Users' own rules can add to these but not lift them;
+        deny rules are also enforced by the privileged helper.
       '';
     };
 
@@ -104,6 +165,8 @@ in {
     system.activationScripts.nix-file-overlay-mappings = lib.stringAfter ["etc"] ''
       mkdir -p /etc/nix-file-overlay
       ln -sf ${etcMappingJson} /etc/nix-file-overlay/etc-mapping.json
+      ln -sf ${metaJson} /etc/nix-file-overlay/meta.json
+      ln -sf ${configToml} /etc/nix-file-overlay/config.toml
       ${lib.concatMapStringsSep "\n" (user: ''
           ln -sf ${mkHmMappingJson user} /etc/nix-file-overlay/hm-mapping-${user}.json
         '')
@@ -7,19 +7,21 @@
   cfg = config.programs.nix-file-overlay;
   pkg = pkgs.callPackage ../../packages/nix-file-overlay {};
 
-  wrappedBin = pkgs.writeShellScriptBin "nix-file-overlay" ''
-    ${lib.optionalString (cfg.repoPath != null) ''export NIX_FILE_OVERLAY_USER_REPO="${cfg.repoPath}"''}
-    ${lib.optionalString (
-      cfg.systemRepoPath != null
-    ) ''export NIX_FILE_OVERLAY_SYSTEM_REPO="${cfg.systemRepoPath}"''}
-    ${lib.optionalString (cfg.editor != null) ''export NIX_FILE_OVERLAY_EDITOR="${cfg.editor}"''}
-    ${
-      if cfg.applyCommand == ""
-      then ''export NIX_FILE_OVERLAY_CMD=""''
-      else lib.optionalString (cfg.applyCommand != null) ''export NIX_FILE_OVERLAY_CMD="${cfg.applyCommand}"''
-    }
-    exec ${pkg}/bin/nix-file-overlay "$@"
-  '';
+  tomlFormat = pkgs.formats.toml {};
+
+  # The dedicated options are shorthands for keys in the config file;
+  # explicit `settings` win over them.
+  configFile = tomlFormat.generate "nix-file-overlay-config.toml" (
+    lib.recursiveUpdate (lib.filterAttrsRecursive (_: v: v != null) {
+      editor = cfg.editor;
+      repos = {
+        user = cfg.repoPath;
+        system = cfg.systemRepoPath;
+      };
+      apply.command = cfg.applyCommand;
+    })
+    cfg.settings
+  );
 in {
   options.programs.nix-file-overlay = {
     enable = lib.mkEnableOption "nix-file-overlay file overlay tool";
@@ -41,7 +43,7 @@ in {
       default = null;
       description = ''
         Path to system NixOS config repo (for /etc/... files).
-        Set this or let the NixOS module propagate it.
+        Falls back to the system config written by the NixOS module.
       '';
     };
 
@@ -59,9 +61,63 @@ in {
         null: auto-detect opencode. "": guidance only. Other: custom command.
       '';
     };
+
+    settings = lib.mkOption {
+      type = tomlFormat.type;
+      default = {};
+      example = lib.literalExpression ''
+        {
+          defaults = {
+            persistent = true;
+            hooks = "always";
+          };
+          validators = [
+            {
+              path = "~/.config/sway/config";
+              command = "sway -C -c {}";
+            }
+          ];
+        }
+      '';
+      description = ''
+        Contents of `~/.config/nix-file-overlay/config.toml`: repos, editor,
+        apply backend, hooks, validators and defaults. Environment variables
+        and command-line flags take precedence.
+      '';
+    };
   };
 
   config = lib.mkIf cfg.enable {
-    home.packages = [wrappedBin];
+    home.packages = [cfg.package];
+
+    xdg.configFile."nix-file-overlay/config.toml".source = configFile;
+
+    # Revert overlays created with --ttl once they expire
+    systemd.user.services.nix-file-overlay-expire = {
+      Unit.Description = "Revert expired nix-file-overlay overlays";
+      Service = {
+        Type = "oneshot";
+        ExecStart = "${cfg.package}/bin/nix-file-overlay --expire";
+      };
+    };
+
+    systemd.user.timers.nix-file-overlay-expire = {
+      Unit.Description = "Check for expired nix-file-overlay overlays";
+      Timer.OnCalendar = "minutely";
+      Install.WantedBy = ["timers.target"];
+    };
+
+    # Revert overlays created with --until-logout, and temporary overlays whose
+    # copies live in the runtime dir, when the user manager stops
+    systemd.user.services.nix-file-overlay-session = {
+      Unit.Description = "Revert session-scoped nix-file-overlay overlays at logout";
+      Service = {
+        Type = "oneshot";
+        RemainAfterExit = true;
+        ExecStart = "${pkgs.coreutils}/bin/true";
+        ExecStop = "${cfg.package}/bin/nix-file-overlay --expire --logout";
+      };
+      Install.WantedBy = ["default.target"];
+    };
   };
 }
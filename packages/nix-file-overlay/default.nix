@@ -8,7 +8,8 @@ rustPlatform.buildRustPackage {
 
   src = ./.;
 
-  cargoHash = "sha256-ota0DjsOQrzxVclAS7u0/vo/RbMN3x5d+ucHkLsL79Q=";
+  # Vendored from the lock file, so adding a dependency needs no hash update.
+  cargoLock.lockFile = ./Cargo.lock;
 
   meta = with lib; {
     description = "Temporarily override Nix-managed files with bind mounts";
@@ -1,12 +1,18 @@
 use anyhow::{bail, Context, Result};
-use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
 use chrono::Utc;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
 use std::fs;
-use std::io::{BufRead, BufReader};
-use std::os::unix::fs::PermissionsExt;
+use std::io::{IsTerminal, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
@@ -23,6 +29,26 @@ struct Cli {
     #[arg(short = 'l', long)]
     list: bool,
 
+    /// With --list: print a JSON array
+    #[arg(long, requires = "list", conflicts_with = "ndjson")]
+    json: bool,
+
+    /// With --list: print one JSON object per line
+    #[arg(long, requires = "list")]
+    ndjson: bool,
+
+    /// With --list: only show overlays that are no longer mounted
+    #[arg(long, requires = "list")]
+    stale: bool,
+
+    /// With --list: only show overlays of this scope
+    #[arg(long, requires = "list", value_enum)]
+    scope: Option<Scope>,
+
+    /// With --list: only show overlays older than this (e.g. 7d)
+    #[arg(long, requires = "list", value_name = "DURATION", value_parser = parse_duration)]
+    older_than: Option<chrono::Duration>,
+
     /// Remove an overlay (unmount, restoring the original symlink)
     #[arg(short = 'r', long, value_name = "PATH")]
     remove: Option<PathBuf>,
@@ -31,11 +57,28 @@ struct Cli {
     #[arg(short = 'a', long, value_name = "PATH")]
     apply: Option<PathBuf>,
 
+    /// Reopen the editor on an existing overlay
+    #[arg(short = 'e', long, value_name = "PATH")]
+    edit: Option<PathBuf>,
+
+    /// Reset an overlay's content to the Nix-managed original
+    #[arg(long, value_name = "PATH")]
+    reset: Option<PathBuf>,
+
     /// Re-apply overlays from registries (internal, used by systemd/activation)
     #[arg(long)]
     restore: bool,
 
-    /// Specify registry file for --restore
+    /// Revert expired overlays (internal, used by the expiry timer)
+    #[arg(long)]
+    expire: bool,
+
+    /// With --expire: also revert overlays created with --until-logout and
+    /// temporary overlays stored in the runtime dir
+    #[arg(long, requires = "expire")]
+    logout: bool,
+
+    /// Specify registry file for --restore or --remove
     #[arg(long, value_name = "PATH")]
     registry: Option<PathBuf>,
 
@@ -43,9 +86,286 @@ struct Cli {
     #[arg(short = 'p', long)]
     persistent: bool,
 
+    /// Keep the overlay temporary even when the config defaults to persistent
+    #[arg(long, conflicts_with = "persistent")]
+    temporary: bool,
+
     /// Skip opening editor after overlay
     #[arg(long)]
     no_edit: bool,
+
+    /// Revert the overlay automatically after this long (e.g. 30m, 2h, 1d)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    ttl: Option<chrono::Duration>,
+
+    /// Revert the overlay automatically when the user session ends
+    #[arg(long)]
+    until_logout: bool,
+
+    /// Start from the file's content in generation N of the system profile
+    /// (for /etc) or the Home-Manager profile (for the home directory)
+    #[arg(long, value_name = "N")]
+    from_generation: Option<u64>,
+
+    /// File mode of the overlay instead of the original's (octal, e.g. 0640)
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    mode: Option<u32>,
+
+    /// Owner of the overlay instead of the original's (USER[:GROUP], names or ids)
+    #[arg(long, value_name = "OWNER", value_parser = parse_owner)]
+    owner: Option<Owner>,
+
+    /// Whether to run post-change hooks (Home-Manager onChange and configured hooks)
+    /// [default: ask]
+    #[arg(long, value_enum)]
+    hooks: Option<HookMode>,
+
+    /// Reload/restart systemd units using a changed /etc file without asking
+    #[arg(long)]
+    reload: bool,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+/// Which registry an overlay is recorded in.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Scope {
+    User,
+    System,
+    Temp,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::User => "user",
+            Scope::System => "system",
+            Scope::Temp => "temp",
+        }
+    }
+}
+
+/// Which overlays `--list` shows, and how.
+#[derive(Clone, Copy, Default)]
+struct ListOptions {
+    json: bool,
+    ndjson: bool,
+    stale: bool,
+    scope: Option<Scope>,
+    older_than: Option<chrono::Duration>,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HookMode {
+    /// Ask before running each hook (skipped when not on a terminal)
+    Ask,
+    /// Run without asking
+    Always,
+    /// Never run
+    Never,
+}
+
+/// How to create a new overlay.
+#[derive(Default)]
+struct OverlayOptions {
+    persistent: bool,
+    no_edit: bool,
+    ttl: Option<chrono::Duration>,
+    until_logout: bool,
+    mode: Option<u32>,
+    owner: Option<Owner>,
+    from_generation: Option<u64>,
+    /// Policy resolved for the path, so it is only evaluated once.
+    policy: Policy,
+}
+
+#[derive(Clone, Copy)]
+struct Owner {
+    uid: u32,
+    gid: Option<u32>,
+}
+
+/// Mode and ownership an overlay is mounted with.
+#[derive(Clone, Copy, PartialEq)]
+struct FileAttrs {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+/// What to do once an overlay's content has changed.
+#[derive(Clone, Copy)]
+struct PostChange {
+    hooks: HookMode,
+    reload_units: bool,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Inspect the generated HM/etc mapping files
+    Mapping {
+        #[command(subcommand)]
+        command: MappingCommands,
+    },
+    /// Search every Nix-managed file from the HM and etc mappings
+    Grep {
+        /// Regular expression to search for
+        pattern: String,
+
+        /// Match case-insensitively
+        #[arg(short = 'i', long)]
+        ignore_case: bool,
+    },
+    /// Edit a transient systemd drop-in for a unit instead of overlaying the unit file
+    Unit {
+        /// Unit name (".service" is assumed when no suffix is given)
+        name: String,
+
+        /// Target a user unit instead of a system unit
+        #[arg(long)]
+        user: bool,
+    },
+    /// Overlay a file and revert it automatically unless confirmed in time
+    Try {
+        /// File path to overlay
+        path: PathBuf,
+
+        /// Seconds before the overlay is reverted
+        #[arg(short = 't', long, default_value_t = TRY_TIMEOUT_SECS)]
+        timeout: u64,
+
+        /// Try the file's content from generation N of its profile
+        #[arg(long, value_name = "N")]
+        from_generation: Option<u64>,
+    },
+    /// Keep a tried overlay by cancelling its pending revert
+    Confirm {
+        /// Overlay to confirm (all pending tries when omitted)
+        path: Option<PathBuf>,
+    },
+    /// List every file that can be overlaid
+    LsManaged {
+        /// Only list files below this path
+        prefix: Option<PathBuf>,
+
+        /// Only list Home-Manager files
+        #[arg(long, conflicts_with = "system")]
+        user: bool,
+
+        /// Only list /etc files
+        #[arg(long)]
+        system: bool,
+
+        /// Only list files generated by Nix (not copied from the repo)
+        #[arg(long)]
+        generated_only: bool,
+
+        /// Print paths only, one per line (for completion and pickers)
+        #[arg(long, conflicts_with = "json")]
+        paths: bool,
+
+        /// Print JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the overlay state as NDJSON, for bar widgets and scripts
+    Status {
+        /// Keep running and print an event whenever the state changes
+        #[arg(long)]
+        follow: bool,
+    },
+    /// Show the audit log of your overlay changes and everyone's changes to
+    /// system paths
+    ///
+    /// Every user's records are also in the journal:
+    /// journalctl MESSAGE_ID=3b1f6c0e9d2a4f57a8e4c6b2d90f1e73
+    Log {
+        /// Only show records for this path or paths below it
+        path: Option<PathBuf>,
+
+        /// Only show records newer than this (e.g. 1h, 7d)
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        since: Option<chrono::Duration>,
+
+        /// Only show this kind of operation
+        #[arg(long, value_enum)]
+        action: Option<AuditAction>,
+
+        /// Only show failed operations
+        #[arg(long)]
+        failed: bool,
+
+        /// Print records as NDJSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check registries, mounts and stored copies for problems
+    Doctor {
+        /// Repair what can be repaired (asks before unmounting leftovers)
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Privileged storage writes and mounts (run through sudo)
+    #[command(hide = true)]
+    Helper {
+        #[command(subcommand)]
+        op: HelperOp,
+    },
+}
+
+#[derive(Subcommand)]
+enum HelperOp {
+    /// Replace a file with the content read from stdin
+    Write { path: PathBuf },
+    /// Overwrite an existing file in place with the content read from stdin
+    WriteInPlace { path: PathBuf },
+    /// Remove a file
+    Remove { path: PathBuf },
+    /// Bind mount a stored copy over a path the system policy allows
+    Mount {
+        source: PathBuf,
+        target: PathBuf,
+        /// Owner the stored copy may have besides the invoking user and root
+        #[arg(long)]
+        owner: Vec<u32>,
+    },
+    /// Install a system unit drop-in with the content read from stdin
+    InstallDropin { path: PathBuf },
+    /// Remove a system unit drop-in
+    RemoveDropin { path: PathBuf },
+    /// Append the audit record read from stdin to the system audit log
+    AppendAudit { path: PathBuf },
+    /// Set a file's mode and ownership
+    SetAttrs {
+        path: PathBuf,
+        #[arg(long, value_parser = parse_mode)]
+        mode: u32,
+        #[arg(long)]
+        uid: u32,
+        #[arg(long)]
+        gid: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum MappingCommands {
+    /// Trace a path through its live symlink chain and show matching mapping entries
+    Scan {
+        /// File path to trace
+        path: PathBuf,
+    },
+}
+
+/// A file as it was in an older generation of a profile.
+#[derive(Serialize, Deserialize, Clone)]
+struct GenerationRef {
+    /// Profile name, `system` or `home-manager`.
+    profile: String,
+    number: u64,
+    /// Store file the content was read from.
+    source: PathBuf,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -56,6 +376,29 @@ struct OverlayEntry {
     created_at: String,
     mapping_key: Option<String>,
     mapping_type: Option<String>,
+    /// Pristine copy of the Nix-managed content, taken before mounting.
+    base_copy: Option<PathBuf>,
+    /// Transient systemd unit that reverts the overlay unless confirmed.
+    try_unit: Option<String>,
+    /// When the pending revert fires (RFC 3339).
+    try_deadline: Option<String>,
+    /// When the overlay expires and is reverted (RFC 3339).
+    expires_at: Option<String>,
+    /// Revert the overlay when the user session ends.
+    #[serde(default)]
+    until_logout: bool,
+    /// Boot the overlay was created in (`/proc/sys/kernel/random/boot_id`).
+    boot_id: Option<String>,
+    /// Permission bits of the stored copy, mirrored from the original.
+    mode: Option<u32>,
+    /// Owner of the stored copy, mirrored from the original.
+    uid: Option<u32>,
+    /// Group of the stored copy, mirrored from the original.
+    gid: Option<u32>,
+    /// Store file the path resolved to when it was overlaid.
+    base_path: Option<PathBuf>,
+    /// Older generation the overlay's content was taken from.
+    generation: Option<GenerationRef>,
 }
 
 type Registry = HashMap<String, OverlayEntry>;
@@ -70,6 +413,8 @@ struct HmMappingEntry {
     repo_relative: Option<String>,
     #[serde(rename = "type")]
     entry_type: Option<String>,
+    #[serde(rename = "onChange")]
+    on_change: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -85,21 +430,162 @@ struct EtcMappingEntry {
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    // The helper runs as root and must not pick up root's config.
+    let config = match cli.command {
+        Some(Commands::Helper { .. }) => Config::default(),
+        // Reverting and restoring overlays must not depend on a config
+        // that may be the very thing that is broken.
+        None if cli.restore || cli.expire || cli.remove.is_some() => {
+            load_config().unwrap_or_else(|e| {
+                eprintln!("Warning: {e:#}; using the default settings");
+                Config::default()
+            })
+        }
+        _ => load_config()?,
+    };
+    let config = &config;
+    let defaults = &config.defaults;
+    let post = PostChange {
+        hooks: cli.hooks.or(defaults.hooks).unwrap_or(HookMode::Ask),
+        reload_units: cli.reload || defaults.reload.unwrap_or(false),
+    };
+
+    if !matches!(cli.command, Some(Commands::Helper { .. })) {
+        migrate_legacy_layout();
+    }
 
+    if let Some(command) = &cli.command {
+        return match command {
+            Commands::Mapping {
+                command: MappingCommands::Scan { path },
+            } => cmd_mapping_scan(path, config),
+            Commands::Grep {
+                pattern,
+                ignore_case,
+            } => cmd_grep(pattern, *ignore_case, config),
+            Commands::Unit { name, user } => {
+                let dropin = dropin_path_for(&normalize_unit_name(name), *user)?;
+                let action = match find_overlay_entry(&dropin) {
+                    Ok(_) => AuditAction::Edit,
+                    Err(_) => AuditAction::Create,
+                };
+                audited(action, &dropin, || cmd_unit(name, *user, config))
+            }
+            Commands::Try {
+                path,
+                timeout,
+                from_generation,
+            } => {
+                let policy = policy_for(&resolve_path(path)?, config)?;
+                audited(AuditAction::Create, path, || {
+                    cmd_try(path, *timeout, *from_generation, policy, post, config)
+                })
+            }
+            Commands::Confirm { path } => cmd_confirm(path.as_deref()),
+            Commands::LsManaged {
+                prefix,
+                user,
+                system,
+                generated_only,
+                paths,
+                json,
+            } => cmd_ls_managed(
+                prefix.as_deref(),
+                *user,
+                *system,
+                *generated_only,
+                *paths,
+                *json,
+                config,
+            ),
+            Commands::Status { follow } => cmd_status(*follow, config),
+            Commands::Log {
+                path,
+                since,
+                action,
+                failed,
+                json,
+            } => cmd_log(path.as_deref(), *since, *action, *failed, *json),
+            Commands::Doctor { fix } => cmd_doctor(*fix, config),
+            Commands::Helper { op } => cmd_helper(op),
+        };
+    }
     if cli.list {
-        return cmd_list();
+        return cmd_list(
+            ListOptions {
+                json: cli.json,
+                ndjson: cli.ndjson,
+                stale: cli.stale,
+                scope: cli.scope,
+                older_than: cli.older_than,
+            },
+            config,
+        );
     }
     if let Some(path) = &cli.remove {
-        return cmd_remove(path);
+        audited(AuditAction::Remove, path, || {
+            cmd_remove(path, cli.registry.as_deref(), config)
+        })?;
+        return after_change(&std::path::absolute(path)?, HookEvent::Remove, post, config);
     }
     if let Some(path) = &cli.apply {
-        return cmd_apply(path);
+        return audited(AuditAction::Apply, path, || cmd_apply(path, config));
+    }
+    if let Some(path) = &cli.edit {
+        return audited(AuditAction::Edit, path, || cmd_edit(path, post, config));
+    }
+    if let Some(path) = &cli.reset {
+        return audited(AuditAction::Reset, path, || cmd_reset(path, post, config));
     }
     if cli.restore {
-        return cmd_restore(cli.registry.as_deref());
+        return cmd_restore(cli.registry.as_deref(), config);
+    }
+    if cli.expire {
+        return cmd_expire(cli.logout, config);
     }
     if let Some(path) = &cli.path {
-        return cmd_overlay(path, cli.persistent, cli.no_edit);
+        let policy = policy_for(&resolve_path(path)?, config)?;
+        if policy.force_try {
+            if cli.persistent || cli.ttl.is_some() || cli.until_logout {
+                bail!(
+                    "Policy requires trying changes to {} with `nix-file-overlay try`",
+                    path.display()
+                );
+            }
+            eprintln!("Policy requires a try; the overlay is reverted unless confirmed.");
+            return audited(AuditAction::Create, path, || {
+                cmd_try(
+                    path,
+                    TRY_TIMEOUT_SECS,
+                    cli.from_generation,
+                    policy,
+                    post,
+                    config,
+                )
+            });
+        }
+        let ttl = match (cli.ttl, &defaults.ttl) {
+            (Some(ttl), _) => Some(ttl),
+            (None, Some(ttl)) => Some(
+                parse_duration(ttl)
+                    .map_err(|e| anyhow::anyhow!("Invalid defaults.ttl in config: {e}"))?,
+            ),
+            (None, None) => None,
+        };
+        let options = OverlayOptions {
+            persistent: cli.persistent
+                || (!cli.temporary && (policy.persistent || defaults.persistent.unwrap_or(false))),
+            no_edit: cli.no_edit,
+            ttl: policy.limit_ttl(ttl),
+            from_generation: cli.from_generation,
+            until_logout: cli.until_logout,
+            mode: cli.mode,
+            owner: cli.owner,
+            policy,
+        };
+        return audited(AuditAction::Create, path, || {
+            cmd_overlay(path, options, post, config)
+        });
     }
 
     bail!("No command specified. Use --help for usage information.");
@@ -120,11 +606,84 @@ fn get_system_data_dir() -> PathBuf {
     PathBuf::from("/var/lib/nix-file-overlay")
 }
 
+/// Storage for temporary overlays. Lives in the user's runtime directory,
+/// which is cleaned up at logout and never survives a reboot.
 fn get_tmp_dir() -> Result<PathBuf> {
+    match std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+        Some(runtime) if runtime.is_dir() => Ok(runtime.join("nix-file-overlay")),
+        _ => get_legacy_tmp_dir(),
+    }
+}
+
+/// Temp storage used before the runtime directory; still consulted so
+/// leftovers get pruned.
+fn get_legacy_tmp_dir() -> Result<PathBuf> {
     let uid = unsafe { libc::getuid() };
     Ok(PathBuf::from(format!("/tmp/nix-file-overlay-{uid}")))
 }
 
+/// The temp storage directory, created private to the user if missing and
+/// verified on every use. Its contents end up bind-mounted over system
+/// files, so nobody else may be able to plant or swap anything in it.
+fn private_tmp_dir() -> Result<PathBuf> {
+    let dir = get_tmp_dir()?;
+    match fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to create {}", dir.display()));
+        }
+    }
+    check_private_dir(&dir, unsafe { libc::getuid() })?;
+    Ok(dir)
+}
+
+fn check_private_dir(dir: &Path, uid: u32) -> Result<()> {
+    let meta =
+        fs::symlink_metadata(dir).with_context(|| format!("Failed to stat {}", dir.display()))?;
+    if !meta.file_type().is_dir() {
+        bail!("{} is not a directory (or is a symlink)", dir.display());
+    }
+    if meta.uid() != uid {
+        bail!(
+            "{} is owned by uid {}, not by uid {uid}; refusing to use it",
+            dir.display(),
+            meta.uid()
+        );
+    }
+    if meta.mode() & 0o077 != 0 {
+        bail!(
+            "{} has mode {:o}, expected 700; refusing to use it",
+            dir.display(),
+            meta.mode() & 0o777
+        );
+    }
+    Ok(())
+}
+
+fn temp_registry_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for dir in [get_tmp_dir(), get_legacy_tmp_dir()].into_iter().flatten() {
+        if (dir.exists() || dir.is_symlink())
+            && let Err(e) = check_private_dir(&dir, unsafe { libc::getuid() })
+        {
+            eprintln!("Warning: ignoring temp storage: {e:#}");
+            continue;
+        }
+        let p = dir.join("registry.json");
+        if !paths.contains(&p) {
+            paths.push(p);
+        }
+    }
+    paths
+}
+
+fn current_boot_id() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/random/boot_id")
+        .ok()
+        .map(|id| id.trim().to_string())
+}
+
 fn is_user_path(path: &Path) -> Result<bool> {
     let home = get_home_dir()?;
     Ok(path.starts_with(&home))
@@ -132,7 +691,7 @@ fn is_user_path(path: &Path) -> Result<bool> {
 
 fn storage_dir_for(path: &Path, persistent: bool) -> Result<PathBuf> {
     if !persistent {
-        return get_tmp_dir();
+        return private_tmp_dir();
     }
     if is_user_path(path)? {
         get_data_dir()
@@ -145,22 +704,152 @@ fn registry_path_for(path: &Path, persistent: bool) -> Result<PathBuf> {
     Ok(storage_dir_for(path, persistent)?.join("registry.json"))
 }
 
-fn encode_path(path: &Path) -> String {
-    URL_SAFE_NO_PAD.encode(path.to_string_lossy().as_bytes())
+/// Registry key for a path: the path itself, or `base64:<bytes>` when it is
+/// not valid UTF-8.
+fn path_key(path: &Path) -> String {
+    match path.to_str() {
+        Some(s) if !s.starts_with("base64:") => s.to_string(),
+        _ => format!(
+            "base64:{}",
+            URL_SAFE_NO_PAD.encode(path.as_os_str().as_bytes())
+        ),
+    }
 }
 
-fn resolve_path(path: &Path) -> Result<PathBuf> {
-    let abs = if path.is_absolute() {
-        path.to_path_buf()
+fn key_path(key: &str) -> PathBuf {
+    key.strip_prefix("base64:")
+        .and_then(|b| URL_SAFE_NO_PAD.decode(b).ok())
+        .map(|bytes| PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+        .unwrap_or_else(|| PathBuf::from(key))
+}
+
+fn path_hash(path: &Path) -> String {
+    format!("{:x}", Sha256::digest(path.as_os_str().as_bytes()))
+}
+
+/// Directory holding an overlay's files: `content` (the mounted copy),
+/// `base` (the pristine original) and `path` (the overlaid path, byte-exact).
+fn overlay_dir_for(storage: &Path, path: &Path) -> PathBuf {
+    storage.join("overlays").join(path_hash(path))
+}
+
+fn is_hashed_layout(stored_copy: &Path) -> bool {
+    stored_copy.file_name() == Some(std::ffi::OsStr::new("content"))
+}
+
+/// Remove an overlay's stored files.
+fn remove_overlay_files(entry: &OverlayEntry) {
+    storage_remove(&entry.stored_copy).ok();
+    if let Some(base) = &entry.base_copy {
+        storage_remove(base).ok();
+    }
+    if is_hashed_layout(&entry.stored_copy) {
+        storage_remove(&entry.stored_copy.with_file_name("path")).ok();
+    }
+}
+
+/// Move stored copies from the old flat `overlays/<base64 path>` layout into
+/// per-path directories. A rename keeps the inode, so mounted overlays stay
+/// live. The system storage is only migrated when running as root.
+fn migrate_legacy_layout() {
+    let mut registries = temp_registry_paths();
+    if let Ok(data_dir) = get_data_dir() {
+        registries.push(data_dir.join("registry.json"));
+    }
+    if is_root() {
+        registries.push(get_system_data_dir().join("registry.json"));
+    }
+
+    for reg_path in registries {
+        let Ok(mut registry) = load_registry(&reg_path) else {
+            continue;
+        };
+        let mut changed = false;
+        for (key, entry) in registry.iter_mut() {
+            if is_hashed_layout(&entry.stored_copy) || !entry.stored_copy.exists() {
+                continue;
+            }
+            match migrate_entry(entry, &key_path(key)) {
+                Ok(()) => changed = true,
+                Err(e) => eprintln!("Warning: could not migrate stored copy for {key}: {e:#}"),
+            }
+        }
+        if changed && let Err(e) = save_registry(&reg_path, &registry) {
+            eprintln!("Warning: {e:#}");
+        }
+    }
+}
+
+fn migrate_entry(entry: &mut OverlayEntry, abs_path: &Path) -> Result<()> {
+    let Some(overlays_dir) = entry.stored_copy.parent() else {
+        bail!("{} has no parent directory", entry.stored_copy.display());
+    };
+    let dir = overlays_dir.join(path_hash(abs_path));
+    let (dir_mode, file_mode) = if is_system_storage(&dir) {
+        (0o755, 0o644)
     } else {
-        std::env::current_dir()?.join(path)
+        (0o700, 0o600)
     };
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(dir_mode)
+        .create(&dir)
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    replace_file(
+        &dir.join("path"),
+        abs_path.as_os_str().as_bytes(),
+        file_mode,
+    )?;
+
+    let content = dir.join("content");
+    fs::rename(&entry.stored_copy, &content)
+        .with_context(|| format!("Failed to move {}", entry.stored_copy.display()))?;
+    entry.stored_copy = content;
+
+    if let Some(base) = &entry.base_copy
+        && base.exists()
+    {
+        let new_base = dir.join("base");
+        fs::rename(base, &new_base)
+            .with_context(|| format!("Failed to move {}", base.display()))?;
+        entry.base_copy = Some(new_base);
+    }
+    Ok(())
+}
+
+fn resolve_path(path: &Path) -> Result<PathBuf> {
+    let abs = normalize_path(path)?;
     if !abs.exists() && !abs.is_symlink() {
         bail!("Path does not exist: {}", abs.display());
     }
     Ok(abs)
 }
 
+/// Make `path` absolute and drop `.` and `..` the way the kernel resolves
+/// them, so policies and the registry only ever see one spelling of a path.
+/// The last component is kept as is: it is the symlink being overlaid.
+fn normalize_path(path: &Path) -> Result<PathBuf> {
+    let mut out = if path.is_absolute() {
+        PathBuf::new()
+    } else {
+        std::env::current_dir()?
+    };
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                // `..` leaves the directory a symlink points to.
+                if let Ok(real) = fs::canonicalize(&out) {
+                    out = real;
+                }
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    Ok(out)
+}
+
 fn get_symlink_target(path: &Path) -> Option<String> {
     fs::read_link(path)
         .ok()
@@ -180,307 +869,3497 @@ fn load_registry(path: &Path) -> Result<Registry> {
 }
 
 fn save_registry(path: &Path, registry: &Registry) -> Result<()> {
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)
-            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
-    }
     let data = serde_json::to_string_pretty(registry)?;
-    fs::write(path, data)
-        .with_context(|| format!("Failed to write registry at {}", path.display()))?;
-    Ok(())
+    storage_write(path, data.as_bytes(), false)
+        .with_context(|| format!("Failed to write registry at {}", path.display()))
 }
 
-fn collect_registry_paths(path: &Path) -> Result<Vec<PathBuf>> {
-    let mut paths = Vec::new();
-    if let Ok(data_dir) = get_data_dir() {
-        paths.push(data_dir.join("registry.json"));
-    }
-    paths.push(get_system_data_dir().join("registry.json"));
-    if let Ok(tmp_dir) = get_tmp_dir() {
-        paths.push(tmp_dir.join("registry.json"));
+// ── File helpers ─────────────────────────────────────────────────────
+
+/// Create `path` exclusively and write `data` to it. Fails if anything,
+/// including a dangling symlink, already exists there.
+fn write_new_file(path: &Path, data: &[u8], mode: u32) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    file.write_all(data)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Replace whatever `path` holds with `data`. The content goes to a fresh
+/// file that is renamed over `path`, so a symlink planted at either name is
+/// replaced instead of followed.
+fn replace_file(path: &Path, data: &[u8], mode: u32) -> Result<()> {
+    replace_file_as(path, data, mode, None)
+}
+
+/// Like `replace_file`, but the new file is handed to `owner` (uid, gid)
+/// before it takes the place of `path`.
+fn replace_file_as(path: &Path, data: &[u8], mode: u32, owner: Option<(u32, u32)>) -> Result<()> {
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::remove_file(&tmp).ok();
+    write_new_file(&tmp, data, mode)?;
+    if let Some((uid, gid)) = owner
+        && let Err(e) = std::os::unix::fs::lchown(&tmp, Some(uid), Some(gid))
+    {
+        fs::remove_file(&tmp).ok();
+        return Err(e).with_context(|| format!("Failed to change owner of {}", tmp.display()));
     }
-    for persistent in [true, false] {
-        if let Ok(p) = registry_path_for(path, persistent) {
-            if !paths.contains(&p) {
-                paths.push(p);
-            }
-        }
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+/// Overwrite an existing regular file in place, keeping its inode (a bind
+/// mount may refer to it). Refuses to follow a symlink.
+fn write_in_place(path: &Path, data: &[u8]) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    if !file.metadata()?.is_file() {
+        bail!("{} is not a regular file", path.display());
     }
-    Ok(paths)
+    file.write_all(data)
+        .with_context(|| format!("Failed to write {}", path.display()))
 }
 
-// ── Sudo wrappers ────────────────────────────────────────────────────
+// ── Storage writes ───────────────────────────────────────────────────
 
-fn is_bind_mounted(path: &Path) -> Result<bool> {
-    // When bind-mounting on a symlink, the kernel resolves it, so /proc/mounts
-    // shows the resolved (canonical) path, not the symlink path. Check both.
-    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
-    let path_str = path.to_string_lossy();
-    let canon_str = canonical.to_string_lossy();
+/// The system storage belongs to root: its copies are mounted over /etc
+/// files and must not be writable by the user who created them.
+fn is_system_storage(path: &Path) -> bool {
+    path.starts_with(get_system_data_dir())
+}
 
-    let file = fs::File::open("/proc/mounts").context("Failed to read /proc/mounts")?;
-    let reader = BufReader::new(file);
-    for line in reader.lines() {
-        let line = line?;
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() >= 2 && (parts[1] == path_str || parts[1] == canon_str) {
-            return Ok(true);
-        }
+fn is_root() -> bool {
+    unsafe { libc::getuid() == 0 }
+}
+
+/// Write a file in overlay storage, going through the privileged helper
+/// for the system storage. Copies there are as readable as the Nix store
+/// files they come from; everything else stays private to the user.
+fn storage_write(path: &Path, data: &[u8], in_place: bool) -> Result<()> {
+    // A copy mirroring a root-owned original is no longer ours to write.
+    if !is_root() && (is_system_storage(path) || (in_place && !is_owned_by_us(path))) {
+        let op = if in_place { "write-in-place" } else { "write" };
+        return run_helper(op, &[], path, Some(data));
+    }
+    let (dir_mode, file_mode) = if is_system_storage(path) {
+        (0o755, 0o644)
+    } else {
+        (0o700, 0o600)
+    };
+    if let Some(parent) = path.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(dir_mode)
+            .create(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+    if in_place {
+        write_in_place(path, data)
+    } else {
+        replace_file_as(path, data, file_mode, user_storage_owner(path))
     }
-    Ok(false)
 }
 
-fn run_sudo_mount(source: &Path, target: &Path) -> Result<()> {
-    let status = Command::new("sudo")
-        .args(["mount", "--bind"])
-        .arg(source)
-        .arg(target)
-        .status()
-        .context("Failed to execute sudo mount")?;
-    if !status.success() {
-        bail!(
-            "sudo mount --bind {} {} failed with exit code {}",
-            source.display(),
-            target.display(),
-            status.code().unwrap_or(-1)
-        );
+/// Owner of the user storage `path` is in, when root writes there (as the
+/// revert of a try does). Files root leaves behind would lock the user out
+/// of their own registry.
+fn user_storage_owner(path: &Path) -> Option<(u32, u32)> {
+    if !is_root() || is_system_storage(path) {
+        return None;
     }
-    Ok(())
+    let meta = fs::metadata(path.parent()?).ok()?;
+    (meta.uid() != 0).then(|| (meta.uid(), meta.gid()))
 }
 
-fn run_sudo_umount(target: &Path) -> Result<()> {
-    let status = Command::new("sudo")
-        .args(["umount"])
-        .arg(target)
-        .status()
-        .context("Failed to execute sudo umount")?;
-    if !status.success() {
-        bail!(
-            "sudo umount {} failed with exit code {}",
-            target.display(),
-            status.code().unwrap_or(-1)
-        );
+fn storage_remove(path: &Path) -> Result<()> {
+    if is_system_storage(path) && !is_root() {
+        return run_helper("remove", &[], path, None);
+    }
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to remove {}", path.display())),
+    }
+    // The per-path overlay directory goes away with its last file.
+    let overlay_file = path
+        .file_name()
+        .is_some_and(|name| ["content", "base", "path"].iter().any(|f| name == *f));
+    if overlay_file
+        && let Some(parent) = path.parent()
+        && parent.parent().and_then(|p| p.file_name()) == Some(std::ffi::OsStr::new("overlays"))
+    {
+        fs::remove_dir(parent).ok();
     }
     Ok(())
 }
 
-// ── Mapping helpers ──────────────────────────────────────────────────
-
-fn get_hm_mapping_path() -> Result<PathBuf> {
-    let username = std::env::var("USER")
-        .or_else(|_| std::env::var("LOGNAME"))
-        .unwrap_or_else(|_| "unknown".to_string());
-    let etc_path = PathBuf::from(format!("/etc/nix-file-overlay/hm-mapping-{username}.json"));
-    if etc_path.exists() {
-        return Ok(etc_path);
+/// Give a stored copy the mode and ownership it is mounted with.
+fn storage_set_attrs(path: &Path, attrs: FileAttrs) -> Result<()> {
+    let meta =
+        fs::symlink_metadata(path).with_context(|| format!("Failed to stat {}", path.display()))?;
+    if file_attrs(&meta) == attrs {
+        return Ok(());
     }
-    let data_dir = get_data_dir()?;
-    Ok(data_dir.join("hm-mapping.json"))
+    let uid = unsafe { libc::getuid() };
+    if is_root() || (!is_system_storage(path) && meta.uid() == uid && attrs.uid == uid) {
+        let local = set_attrs(path, attrs);
+        if local.is_ok() || is_root() {
+            return local;
+        }
+    }
+    let options = [
+        format!("--mode={:o}", attrs.mode).into(),
+        format!("--uid={}", attrs.uid).into(),
+        format!("--gid={}", attrs.gid).into(),
+    ];
+    run_helper("set-attrs", &options, path, None)
 }
 
-fn find_mapping_key_for_path(abs_path: &Path) -> Result<Option<(String, String)>> {
-    let home = get_home_dir()?;
-
-    if abs_path.starts_with(&home) {
-        let hm_mapping_path = get_hm_mapping_path()?;
-        if hm_mapping_path.exists() {
-            let data = fs::read_to_string(&hm_mapping_path)?;
-            let mapping: HashMap<String, HmMappingEntry> = serde_json::from_str(&data)?;
-
-            let rel = abs_path.strip_prefix(&home).unwrap();
-            let rel_str = format!("./{}", rel.display());
-            let rel_str_no_dot = rel.to_string_lossy().to_string();
-
-            for (key, entry) in &mapping {
-                let target = entry.target.as_deref().unwrap_or(key);
-                let target_clean = target.strip_prefix("./").unwrap_or(target);
-
-                if target_clean == rel_str.strip_prefix("./").unwrap_or(&rel_str)
-                    || target_clean == rel_str_no_dot
-                {
-                    return Ok(Some((key.clone(), "hm".to_string())));
-                }
+fn set_attrs(path: &Path, attrs: FileAttrs) -> Result<()> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    std::os::unix::fs::fchown(&file, Some(attrs.uid), Some(attrs.gid))
+        .with_context(|| format!("Failed to change owner of {}", path.display()))?;
+    file.set_permissions(fs::Permissions::from_mode(attrs.mode))
+        .with_context(|| format!("Failed to change mode of {}", path.display()))
+}
 
-                if entry.recursive.unwrap_or(false) {
-                    let target_path = Path::new(target_clean);
-                    let rel_path = Path::new(rel_str.strip_prefix("./").unwrap_or(&rel_str));
-                    if rel_path.starts_with(target_path) {
-                        return Ok(Some((key.clone(), "hm".to_string())));
-                    }
-                }
-            }
-        }
+fn file_attrs(meta: &fs::Metadata) -> FileAttrs {
+    FileAttrs {
+        mode: meta.mode() & 0o7777,
+        uid: meta.uid(),
+        gid: meta.gid(),
     }
+}
 
-    let etc_mapping_path = Path::new("/etc/nix-file-overlay/etc-mapping.json");
-    if etc_mapping_path.exists() && abs_path.starts_with("/etc/") {
-        let data = fs::read_to_string(etc_mapping_path)?;
-        let mapping: HashMap<String, EtcMappingEntry> = serde_json::from_str(&data)?;
+fn entry_attrs(entry: &OverlayEntry) -> Option<FileAttrs> {
+    Some(FileAttrs {
+        mode: entry.mode?,
+        uid: entry.uid?,
+        gid: entry.gid?,
+    })
+}
 
-        for (key, entry) in &mapping {
-            if let Some(p) = &entry.path {
-                if Path::new(p) == abs_path {
-                    return Ok(Some((key.clone(), "etc".to_string())));
-                }
-            }
+fn is_owned_by_us(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .map(|m| m.uid() == unsafe { libc::getuid() })
+        .unwrap_or(true)
+}
+
+/// Mode and ownership for an overlay of `abs_path`: the original's as seen
+/// through the symlink, unless overridden. Files in the home directory stay
+/// owned by the user and writable by them.
+fn overlay_attrs(abs_path: &Path, options: &OverlayOptions) -> Result<FileAttrs> {
+    let meta =
+        fs::metadata(abs_path).with_context(|| format!("Failed to stat {}", abs_path.display()))?;
+    let mut attrs = file_attrs(&meta);
+    if is_user_path(abs_path)? {
+        attrs.uid = unsafe { libc::getuid() };
+        attrs.gid = unsafe { libc::getgid() };
+        attrs.mode |= 0o200;
+    }
+    if let Some(mode) = options.mode {
+        attrs.mode = mode;
+    }
+    if let Some(owner) = options.owner {
+        attrs.uid = owner.uid;
+        if let Some(gid) = owner.gid {
+            attrs.gid = gid;
         }
     }
-
-    Ok(None)
+    Ok(attrs)
 }
 
-// ── Overlay command ──────────────────────────────────────────────────
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|m| *m <= 0o7777)
+        .ok_or_else(|| format!("invalid mode '{s}' (expected octal, e.g. 0644)"))
+}
 
-fn cmd_overlay(path: &Path, persistent: bool, no_edit: bool) -> Result<()> {
-    let abs_path = resolve_path(path)?;
+fn parse_owner(s: &str) -> Result<Owner, String> {
+    let (user, group) = match s.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (s, None),
+    };
+    let uid = lookup_id("/etc/passwd", user).ok_or_else(|| format!("unknown user '{user}'"))?;
+    let gid = match group {
+        Some(group) => {
+            Some(lookup_id("/etc/group", group).ok_or_else(|| format!("unknown group '{group}'"))?)
+        }
+        None => None,
+    };
+    Ok(Owner { uid, gid })
+}
 
-    if !abs_path.is_symlink() {
-        bail!(
-            "Only Nix-managed files (symlinks) can be overlaid. \
-             {} is not a symlink.",
-            abs_path.display()
-        );
+/// Resolve a user or group name (or numeric id) through /etc/passwd or
+/// /etc/group.
+fn lookup_id(db: &str, name: &str) -> Option<u32> {
+    if let Ok(id) = name.parse() {
+        return Some(id);
     }
+    fs::read_to_string(db).ok()?.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        (fields.next()? == name)
+            .then(|| fields.nth(1)?.parse().ok())
+            .flatten()
+    })
+}
 
-    if !abs_path.is_file() {
+fn run_helper(op: &str, options: &[OsString], path: &Path, input: Option<&[u8]>) -> Result<()> {
+    let exe = std::env::current_exe().context("Failed to locate own executable")?;
+    let mut child = Command::new("sudo")
+        .arg(exe)
+        .args(["helper", op])
+        .args(options)
+        .arg(path)
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .context("Failed to execute sudo")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.unwrap_or_default())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
         bail!(
-            "Symlink does not point to a regular file: {}",
-            abs_path.display()
+            "Privileged {op} of {} failed with exit code {}",
+            path.display(),
+            status.code().unwrap_or(-1)
         );
     }
+    Ok(())
+}
+
+/// Runs as root on behalf of the `storage_*` functions. Only touches paths
+/// inside the system storage or the invoking user's temp storage.
+fn cmd_helper(op: &HelperOp) -> Result<()> {
+    if !is_root() {
+        bail!("The helper must run as root");
+    }
+    let path = match op {
+        HelperOp::Write { path }
+        | HelperOp::WriteInPlace { path }
+        | HelperOp::Remove { path }
+        | HelperOp::SetAttrs { path, .. }
+        | HelperOp::Mount { source: path, .. }
+        | HelperOp::InstallDropin { path }
+        | HelperOp::RemoveDropin { path }
+        | HelperOp::AppendAudit { path } => path,
+    };
+    let plain = is_plain_path(path);
+    let allowed = match op {
+        HelperOp::Mount { .. } => helper_may_mount_from(path),
+        HelperOp::InstallDropin { .. } | HelperOp::RemoveDropin { .. } => is_system_dropin(path),
+        HelperOp::AppendAudit { .. } => *path == system_audit_log_path(),
+        _ => helper_may_touch(path),
+    };
+    if !plain || !allowed {
+        bail!(
+            "Refusing to touch {} outside overlay storage",
+            path.display()
+        );
+    }
+
+    match op {
+        HelperOp::Write { .. } | HelperOp::WriteInPlace { .. } => {
+            let mut data = Vec::new();
+            std::io::stdin().read_to_end(&mut data)?;
+            storage_write(path, &data, matches!(op, HelperOp::WriteInPlace { .. }))
+        }
+        HelperOp::Remove { .. } => storage_remove(path),
+        HelperOp::Mount { target, owner, .. } => {
+            // The same precondition as for overlays: only Nix-managed files.
+            let nix_managed = target.is_symlink()
+                && fs::canonicalize(target).is_ok_and(|t| t.starts_with("/nix/store"));
+            if !is_plain_path(target) || !nix_managed {
+                bail!(
+                    "Refusing to mount over {}: not a symlink into the Nix store",
+                    target.display()
+                );
+            }
+            system_policy_allows(target)?;
+            verify_mount_source(path, owner)?;
+            bind_mount(path, target)
+        }
+        HelperOp::InstallDropin { .. } => {
+            system_policy_allows(path)?;
+            let mut data = Vec::new();
+            std::io::stdin().read_to_end(&mut data)?;
+            write_system_dropin(path, &data)
+        }
+        HelperOp::RemoveDropin { .. } => remove_system_dropin(path),
+        HelperOp::AppendAudit { .. } => {
+            let mut data = Vec::new();
+            std::io::stdin().read_to_end(&mut data)?;
+            let mut record: AuditRecord =
+                serde_json::from_slice(&data).context("Invalid audit record")?;
+            // Users must not be able to log changes in someone else's name.
+            record.uid = invoking_uid();
+            record.user = user_name(record.uid);
+            record.time = Utc::now().to_rfc3339();
+            verify_audit_record(&mut record)?;
+            append_record(path, &record, 0o644)
+        }
+        HelperOp::SetAttrs { mode, uid, gid, .. } => set_attrs(
+            path,
+            FileAttrs {
+                mode: *mode,
+                uid: *uid,
+                gid: *gid,
+            },
+        ),
+    }
+}
+
+fn helper_may_touch(path: &Path) -> bool {
+    if is_system_storage(path) {
+        return true;
+    }
+    // Temp copies of root-owned files live in the invoking user's storage.
+    let Some(uid) = std::env::var("SUDO_UID")
+        .ok()
+        .and_then(|u| u.parse::<u32>().ok())
+    else {
+        return false;
+    };
+    [
+        PathBuf::from(format!("/run/user/{uid}/nix-file-overlay")),
+        PathBuf::from(format!("/tmp/nix-file-overlay-{uid}")),
+    ]
+    .iter()
+    .any(|dir| path.starts_with(dir) && check_private_dir(dir, uid).is_ok())
+}
+
+/// Refuse paths the system config denies. Only the system config counts
+/// here: users must not be able to lift its rules with their own.
+/// Whether `path` is absolute and spelled without `.`, `..` or doubled
+/// slashes. `components()` silently drops the first and last two, so the
+/// spelling itself is compared.
+fn is_plain_path(path: &Path) -> bool {
+    let rebuilt: PathBuf = path.components().collect();
+    path.is_absolute()
+        && rebuilt.as_os_str() == path.as_os_str()
+        && path.components().all(|c| {
+            matches!(
+                c,
+                std::path::Component::RootDir | std::path::Component::Normal(_)
+            )
+        })
+}
+
+fn system_policy_allows(path: &Path) -> Result<()> {
+    let system = read_config_file(Path::new(SYSTEM_CONFIG_PATH))?;
+    if let Some(reason) = resolve_policy(&system.policies, path)?.deny {
+        bail!(
+            "System policy forbids overlays of {} ({reason})",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Mount sources may also be persistent copies in the invoking user's data
+/// dir; `verify_mount_source` checks that the user owns them.
+fn helper_may_mount_from(source: &Path) -> bool {
+    if helper_may_touch(source) {
+        return true;
+    }
+    let Some(uid) = std::env::var("SUDO_UID")
+        .ok()
+        .and_then(|u| u.parse::<u32>().ok())
+    else {
+        return false;
+    };
+    home_of(uid).is_some_and(|home| source.starts_with(home.join(".local/share/nix-file-overlay")))
+}
+
+/// Home directory of `uid` from /etc/passwd.
+fn home_of(uid: u32) -> Option<PathBuf> {
+    fs::read_to_string("/etc/passwd")
+        .ok()?
+        .lines()
+        .find_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            if fields.get(2)?.parse::<u32>().ok()? != uid {
+                return None;
+            }
+            fields.get(5).map(PathBuf::from)
+        })
+}
+
+/// The user, system and temp registries.
+fn known_registry_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Ok(data_dir) = get_data_dir() {
+        paths.push(data_dir.join("registry.json"));
+    }
+    paths.push(get_system_data_dir().join("registry.json"));
+    for p in temp_registry_paths() {
+        if !paths.contains(&p) {
+            paths.push(p);
+        }
+    }
+    paths
+}
+
+fn collect_registry_paths(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = known_registry_paths();
+    for persistent in [true, false] {
+        if let Ok(p) = registry_path_for(path, persistent)
+            && !paths.contains(&p)
+        {
+            paths.push(p);
+        }
+    }
+    Ok(paths)
+}
+
+/// Load the entries of the user, system and temp registries, tagged with
+/// their scope.
+fn load_all_overlays() -> Vec<(String, OverlayEntry, String)> {
+    let mut entries: Vec<(String, OverlayEntry, String)> = Vec::new();
+
+    if let Ok(data_dir) = get_data_dir() {
+        let reg_path = data_dir.join("registry.json");
+        if let Ok(reg) = load_registry(&reg_path) {
+            for (path, entry) in reg {
+                entries.push((path, entry, "user".to_string()));
+            }
+        }
+    }
+
+    let sys_reg_path = get_system_data_dir().join("registry.json");
+    if let Ok(reg) = load_registry(&sys_reg_path) {
+        for (path, entry) in reg {
+            entries.push((path, entry, "system".to_string()));
+        }
+    }
+
+    for reg_path in temp_registry_paths() {
+        if let Ok(reg) = load_registry(&reg_path) {
+            for (path, entry) in reg {
+                if !entries.iter().any(|(p, _, _)| p == &path) {
+                    entries.push((path, entry, "temp".to_string()));
+                }
+            }
+        }
+    }
+
+    entries
+}
+
+/// Drop temporary entries created in a previous boot from the temp
+/// registries, along with their stored copies. Entries that predate boot
+/// tracking are dropped once their mount is gone. Returns the pruned paths.
+fn prune_dead_temp_entries() -> Vec<String> {
+    let Some(boot_id) = current_boot_id() else {
+        return Vec::new();
+    };
+    let mut pruned = Vec::new();
+
+    for reg_path in temp_registry_paths() {
+        let Ok(mut registry) = load_registry(&reg_path) else {
+            continue;
+        };
+        let dead: Vec<String> = registry
+            .iter()
+            .filter(|(_, e)| !e.persistent && !is_dropin_entry(e))
+            .filter(|(path, e)| match &e.boot_id {
+                Some(id) => *id != boot_id,
+                None => !is_bind_mounted(&key_path(path)).unwrap_or(true),
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        if dead.is_empty() {
+            continue;
+        }
+
+        for path in &dead {
+            if let Some(entry) = registry.remove(path) {
+                remove_overlay_files(&entry);
+            }
+        }
+        if save_registry(&reg_path, &registry).is_ok() {
+            pruned.extend(dead);
+        }
+    }
+
+    pruned
+}
+
+/// Find the registry entry for `abs_path` and the registry holding it.
+fn find_overlay_entry(abs_path: &Path) -> Result<(OverlayEntry, PathBuf)> {
+    let path_str = path_key(abs_path);
+
+    for reg_path in &collect_registry_paths(abs_path)? {
+        let registry = match load_registry(reg_path) {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+        if let Some(entry) = registry.get(&path_str) {
+            return Ok((entry.clone(), reg_path.clone()));
+        }
+    }
+
+    bail!(
+        "No overlay found for {}. Overlay the file first.",
+        abs_path.display()
+    )
+}
+
+// ── Mount table ──────────────────────────────────────────────────────
+
+/// One line of `/proc/self/mountinfo`.
+struct MountInfo {
+    mount_id: u32,
+    parent_id: u32,
+    /// `major:minor` of the mounted filesystem.
+    dev: String,
+    /// Path of the mount's root inside its filesystem.
+    root: PathBuf,
+    mount_point: PathBuf,
+}
+
+struct MountTable {
+    mounts: Vec<MountInfo>,
+}
+
+/// How the mounts at an overlaid path relate to the overlay's stored copy.
+#[derive(Clone, Copy, PartialEq)]
+enum MountState {
+    Unmounted,
+    /// Exactly one mount, and it is the stored copy.
+    Ours,
+    /// Something is mounted there, but not the stored copy.
+    Foreign,
+    /// Several mounts are stacked there, one of them the stored copy.
+    Stacked {
+        layers: usize,
+        ours_on_top: bool,
+    },
+}
+
+impl MountTable {
+    fn read() -> Result<Self> {
+        let text =
+            fs::read("/proc/self/mountinfo").context("Failed to read /proc/self/mountinfo")?;
+        Ok(Self::parse(&text))
+    }
+
+    fn parse(text: &[u8]) -> Self {
+        let mounts = text
+            .split(|b| *b == b'\n')
+            .filter_map(|line| {
+                // id parent major:minor root mount-point options ...
+                let mut fields = line.split(|b| *b == b' ');
+                let mount_id = std::str::from_utf8(fields.next()?).ok()?.parse().ok()?;
+                let parent_id = std::str::from_utf8(fields.next()?).ok()?.parse().ok()?;
+                let dev = String::from_utf8(fields.next()?.to_vec()).ok()?;
+                let root = unescape_mount_path(fields.next()?);
+                let mount_point = unescape_mount_path(fields.next()?);
+                Some(MountInfo {
+                    mount_id,
+                    parent_id,
+                    dev,
+                    root,
+                    mount_point,
+                })
+            })
+            .collect();
+        Self { mounts }
+    }
+
+    /// Mounts at `path`, bottom layer first. A bind mount on a symlink lands
+    /// on the resolved path, so both are checked.
+    fn mounts_at(&self, path: &Path) -> Vec<&MountInfo> {
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let mut mounts: Vec<&MountInfo> = self
+            .mounts
+            .iter()
+            .filter(|m| m.mount_point == path || m.mount_point == canonical)
+            .collect();
+        // A layer's parent is the mount it covers.
+        mounts.sort_by_key(|m| {
+            std::iter::successors(Some(*m), |m| {
+                self.mounts
+                    .iter()
+                    .find(|p| p.mount_id == m.parent_id && p.mount_point == m.mount_point)
+            })
+            .count()
+        });
+        mounts
+    }
+
+    fn is_mounted(&self, path: &Path) -> bool {
+        !self.mounts_at(path).is_empty()
+    }
+
+    /// Whether `mount` shows `stored_copy`: same device, and its root is the
+    /// copy's path inside that filesystem.
+    fn is_our_mount(&self, mount: &MountInfo, stored_copy: &Path) -> bool {
+        let Ok(meta) = fs::metadata(stored_copy) else {
+            return false;
+        };
+        if mount.dev != dev_string(meta.dev()) {
+            return false;
+        }
+        let copy = fs::canonicalize(stored_copy).unwrap_or_else(|_| stored_copy.to_path_buf());
+        // The filesystem mount the copy is reached through.
+        let Some(host) = self
+            .mounts
+            .iter()
+            .filter(|m| m.dev == mount.dev && copy.starts_with(&m.mount_point))
+            .max_by_key(|m| m.mount_point.as_os_str().len())
+        else {
+            return false;
+        };
+        let Ok(rel) = copy.strip_prefix(&host.mount_point) else {
+            return false;
+        };
+        mount.root == host.root.join(rel)
+    }
+
+    /// Where the file or directory shown by a bind mount can be reached,
+    /// and whether it has been deleted since.
+    fn source_of(&self, mount: &MountInfo) -> Option<(PathBuf, bool)> {
+        let raw = mount.root.as_os_str().as_bytes();
+        let (raw, deleted) = match raw.strip_suffix(b"//deleted") {
+            Some(rest) => (rest, true),
+            None => (raw, false),
+        };
+        let root = Path::new(std::ffi::OsStr::from_bytes(raw));
+        let host = self
+            .mounts
+            .iter()
+            .filter(|h| h.mount_id != mount.mount_id && h.dev == mount.dev)
+            .filter(|h| root.starts_with(&h.root))
+            .max_by_key(|h| h.root.as_os_str().len())?;
+        let rel = root.strip_prefix(&host.root).ok()?;
+        Some((host.mount_point.join(rel), deleted))
+    }
+
+    fn overlay_state(&self, path: &Path, stored_copy: &Path) -> MountState {
+        let mounts = self.mounts_at(path);
+        let ours: Vec<bool> = mounts
+            .iter()
+            .map(|m| self.is_our_mount(m, stored_copy))
+            .collect();
+        match (mounts.len(), ours.iter().any(|o| *o)) {
+            (0, _) => MountState::Unmounted,
+            (_, false) => MountState::Foreign,
+            (1, true) => MountState::Ours,
+            (layers, true) => MountState::Stacked {
+                layers,
+                ours_on_top: ours.last() == Some(&true),
+            },
+        }
+    }
+}
+
+/// Undo the octal escapes (`\040` for space etc.) the kernel uses in
+/// mountinfo paths.
+fn unescape_mount_path(raw: &[u8]) -> PathBuf {
+    let mut out = Vec::with_capacity(raw.len());
+    let mut i = 0;
+    while i < raw.len() {
+        if raw[i] == b'\\'
+            && let Some(digits) = raw.get(i + 1..i + 4)
+            && digits.iter().all(|d| (b'0'..=b'7').contains(d))
+        {
+            out.push(digits.iter().fold(0u8, |acc, d| (acc << 3) | (d - b'0')));
+            i += 4;
+        } else {
+            out.push(raw[i]);
+            i += 1;
+        }
+    }
+    PathBuf::from(std::ffi::OsString::from_vec(out))
+}
+
+fn dev_string(dev: u64) -> String {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    format!("{major}:{minor}")
+}
+
+fn is_bind_mounted(path: &Path) -> Result<bool> {
+    Ok(MountTable::read()?.is_mounted(path))
+}
+
+// ── Sudo wrappers ────────────────────────────────────────────────────
+
+/// Make sure the file about to be mounted over a system path is the one we
+/// wrote: a regular file owned by us, root or one of `owners`, with no other
+/// links, in a directory owned the same way that nobody else can write to.
+fn verify_mount_source(source: &Path, owners: &[u32]) -> Result<()> {
+    let meta = fs::symlink_metadata(source)
+        .with_context(|| format!("Failed to stat mount source {}", source.display()))?;
+    let uid = invoking_uid();
+    if !meta.file_type().is_file() {
+        bail!(
+            "Mount source {} is not a regular file (or is a symlink)",
+            source.display()
+        );
+    }
+    let trusted = |owner: u32| owner == uid || owner == 0 || owners.contains(&owner);
+    if !trusted(meta.uid()) {
+        bail!(
+            "Mount source {} is owned by uid {}, refusing to mount it",
+            source.display(),
+            meta.uid()
+        );
+    }
+    if meta.nlink() != 1 {
+        bail!(
+            "Mount source {} has {} hard links, refusing to mount it",
+            source.display(),
+            meta.nlink()
+        );
+    }
+    if let Some(parent) = source.parent() {
+        let dir = fs::symlink_metadata(parent)?;
+        if !dir.file_type().is_dir() || !trusted(dir.uid()) || dir.mode() & 0o022 != 0 {
+            bail!(
+                "Mount source directory {} is writable by others, refusing to mount from it",
+                parent.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Users besides us and root who may own the stored copy of `entry`: the one
+/// it was made for and the owner of its registry. The restore service runs as
+/// root and mounts copies from users' own data dirs.
+fn mount_owners(entry: &OverlayEntry, reg_path: &Path) -> Vec<u32> {
+    entry
+        .uid
+        .into_iter()
+        .chain(fs::metadata(reg_path).ok().map(|m| m.uid()))
+        .collect()
+}
+
+/// The user we act for: the caller of sudo inside the helper, else ourselves.
+fn invoking_uid() -> u32 {
+    let uid = unsafe { libc::getuid() };
+    match std::env::var("SUDO_UID") {
+        Ok(sudo_uid) if uid == 0 => sudo_uid.parse().unwrap_or(uid),
+        _ => uid,
+    }
+}
+
+/// Mount through the helper, which enforces the system policy on `target`.
+fn run_sudo_mount(source: &Path, target: &Path, owners: &[u32]) -> Result<()> {
+    verify_mount_source(source, owners)?;
+    if is_root() {
+        return bind_mount(source, target);
+    }
+    let mut options: Vec<OsString> = owners
+        .iter()
+        .map(|owner| format!("--owner={owner}").into())
+        .collect();
+    options.push(source.into());
+    run_helper("mount", &options, target, None)
+}
+
+fn bind_mount(source: &Path, target: &Path) -> Result<()> {
+    let status = Command::new("mount")
+        .arg("--bind")
+        .arg(source)
+        .arg(target)
+        .status()
+        .context("Failed to execute mount")?;
+    if !status.success() {
+        bail!(
+            "mount --bind {} {} failed with exit code {}",
+            source.display(),
+            target.display(),
+            status.code().unwrap_or(-1)
+        );
+    }
+    Ok(())
+}
+
+fn run_sudo_umount(target: &Path) -> Result<()> {
+    let status = Command::new("sudo")
+        .args(["umount"])
+        .arg(target)
+        .status()
+        .context("Failed to execute sudo umount")?;
+    if !status.success() {
+        bail!(
+            "sudo umount {} failed with exit code {}",
+            target.display(),
+            status.code().unwrap_or(-1)
+        );
+    }
+    Ok(())
+}
+
+// ── Mapping helpers ──────────────────────────────────────────────────
+
+fn get_hm_mapping_path() -> Result<PathBuf> {
+    let username = std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .unwrap_or_else(|_| "unknown".to_string());
+    let etc_path = PathBuf::from(format!("/etc/nix-file-overlay/hm-mapping-{username}.json"));
+    if etc_path.exists() {
+        return Ok(etc_path);
+    }
+    let data_dir = get_data_dir()?;
+    Ok(data_dir.join("hm-mapping.json"))
+}
+
+const ETC_MAPPING_PATH: &str = "/etc/nix-file-overlay/etc-mapping.json";
+
+fn load_hm_mapping() -> Result<Option<HashMap<String, HmMappingEntry>>> {
+    let hm_mapping_path = get_hm_mapping_path()?;
+    if !hm_mapping_path.exists() {
+        return Ok(None);
+    }
+    let data = fs::read_to_string(&hm_mapping_path)?;
+    let mapping = serde_json::from_str(&data)
+        .with_context(|| format!("Failed to parse {}", hm_mapping_path.display()))?;
+    Ok(Some(mapping))
+}
+
+fn load_etc_mapping() -> Result<Option<HashMap<String, EtcMappingEntry>>> {
+    let etc_mapping_path = Path::new(ETC_MAPPING_PATH);
+    if !etc_mapping_path.exists() {
+        return Ok(None);
+    }
+    let data = fs::read_to_string(etc_mapping_path)?;
+    let mapping = serde_json::from_str(&data)
+        .with_context(|| format!("Failed to parse {}", etc_mapping_path.display()))?;
+    Ok(Some(mapping))
+}
+
+/// A mapping entry that claims to manage a given path.
+struct MappingMatch {
+    key: String,
+    kind: String,
+    source: Option<String>,
+    /// Path below the entry's target, for files inside a recursive HM entry.
+    sub_path: Option<PathBuf>,
+    /// Home-Manager `onChange` script of the entry.
+    on_change: Option<String>,
+}
+
+fn find_mapping_for_path(abs_path: &Path) -> Result<Option<MappingMatch>> {
+    let home = get_home_dir()?;
+
+    if abs_path.starts_with(&home)
+        && let Some(mapping) = load_hm_mapping()?
+    {
+        let rel = abs_path.strip_prefix(&home).unwrap();
+
+        for (key, entry) in &mapping {
+            let target = entry.target.as_deref().unwrap_or(key);
+            let target_path = Path::new(target.strip_prefix("./").unwrap_or(target));
+
+            let sub_path = if rel == target_path {
+                None
+            } else if entry.recursive.unwrap_or(false) && rel.starts_with(target_path) {
+                rel.strip_prefix(target_path).ok().map(Path::to_path_buf)
+            } else {
+                continue;
+            };
+
+            return Ok(Some(MappingMatch {
+                key: key.clone(),
+                kind: "hm".to_string(),
+                source: entry.source.clone(),
+                sub_path,
+                on_change: entry.on_change.clone(),
+            }));
+        }
+    }
+
+    if abs_path.starts_with("/etc/")
+        && let Some(mapping) = load_etc_mapping()?
+    {
+        for (key, entry) in &mapping {
+            if entry.path.as_deref().map(Path::new) == Some(abs_path) {
+                return Ok(Some(MappingMatch {
+                    key: key.clone(),
+                    kind: "etc".to_string(),
+                    source: entry.source.clone(),
+                    sub_path: None,
+                    on_change: None,
+                }));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Follow `path` through every symlink hop, returning each path visited.
+///
+/// Intermediate directories are canonicalized at every hop, so links that
+/// live behind a symlinked parent (e.g. `/etc/static/...`) are still followed.
+fn symlink_chain(path: &Path) -> Vec<PathBuf> {
+    let mut chain = vec![path.to_path_buf()];
+    let mut current = path.to_path_buf();
+
+    for _ in 0..40 {
+        let (Some(parent), Some(name)) = (current.parent(), current.file_name()) else {
+            break;
+        };
+        let real = fs::canonicalize(parent)
+            .map(|p| p.join(name))
+            .unwrap_or_else(|_| current.clone());
+        if real != current {
+            chain.push(real.clone());
+        }
+        let Ok(target) = fs::read_link(&real) else {
+            break;
+        };
+        current = if target.is_absolute() {
+            target
+        } else {
+            real.parent().unwrap_or(Path::new("/")).join(target)
+        };
+        chain.push(current.clone());
+    }
+
+    chain
+}
+
+/// Check a mapping entry's `source` against the live symlink chain of
+/// `abs_path`. Returns a description of the mismatch, if any.
+fn mapping_mismatch(abs_path: &Path, m: &MappingMatch) -> Option<String> {
+    let source = m.source.as_deref()?;
+    let expected = match &m.sub_path {
+        Some(sub) => Path::new(source).join(sub),
+        None => PathBuf::from(source),
+    };
+
+    let chain = symlink_chain(abs_path);
+    if chain.contains(&expected) {
+        return None;
+    }
+    if let (Ok(live), Ok(mapped)) = (fs::canonicalize(abs_path), fs::canonicalize(&expected))
+        && live == mapped
+    {
+        return None;
+    }
+
+    Some(format!(
+        "mapping entry '{}' ({}) points at {}, but {} resolves via {}",
+        m.key,
+        m.kind,
+        expected.display(),
+        abs_path.display(),
+        chain
+            .iter()
+            .skip(1)
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(" -> ")
+    ))
+}
+
+/// Look up the mapping entry for `abs_path` and warn if it no longer matches
+/// the live generation.
+fn lookup_mapping_checked(abs_path: &Path) -> Result<(Option<MappingMatch>, Option<String>)> {
+    let Some(m) = find_mapping_for_path(abs_path)? else {
+        return Ok((None, None));
+    };
+    let mismatch = mapping_mismatch(abs_path, &m);
+    if let Some(reason) = &mismatch {
+        eprintln!("Warning: mapping is out of date with the live generation: {reason}");
+        eprintln!("  (HM activation may have run separately, or a rebuild stopped halfway.)");
+    }
+    Ok((Some(m), mismatch))
+}
+
+// ── Mapping scan command ─────────────────────────────────────────────
+
+fn cmd_mapping_scan(path: &Path, config: &Config) -> Result<()> {
+    let abs_path = resolve_path(path)?;
+    print_mapping_scan(&abs_path, config)
+}
+
+/// Print the live symlink chain of `abs_path` and every mapping entry whose
+/// source appears in it, without trusting the target-based lookup.
+fn print_mapping_scan(abs_path: &Path, config: &Config) -> Result<()> {
+    let chain = symlink_chain(abs_path);
+
+    println!("Live symlink chain:");
+    for (i, hop) in chain.iter().enumerate() {
+        let prefix = if i == 0 { "   " } else { "-> " };
+        println!("  {prefix}{}", hop.display());
+    }
+
+    let mut found = Vec::new();
+    if let Some(mapping) = load_hm_mapping()? {
+        for (key, entry) in &mapping {
+            let Some(source) = entry.source.as_deref() else {
+                continue;
+            };
+            let source = Path::new(source);
+            let hit = if entry.recursive.unwrap_or(false) {
+                chain.iter().any(|hop| hop.starts_with(source))
+            } else {
+                chain.iter().any(|hop| hop == source)
+            };
+            if hit {
+                let repo = entry.repo_relative.as_deref().unwrap_or("-");
+                let ty = entry.entry_type.as_deref().unwrap_or("-");
+                found.push(format!("hm   {key}  (type {ty}, repo {repo})"));
+            }
+        }
+    }
+    if let Some(mapping) = load_etc_mapping()? {
+        for (key, entry) in &mapping {
+            let Some(source) = entry.source.as_deref() else {
+                continue;
+            };
+            if chain.iter().any(|hop| hop == Path::new(source)) {
+                let defined = entry.defined_in.as_deref().unwrap_or(&[]).join(", ");
+                found.push(format!("etc  {key}  (defined in {defined})"));
+            }
+        }
+    }
+
+    println!();
+    if found.is_empty() {
+        println!("No mapping entry has a source in the live chain.");
+    } else {
+        println!("Mapping entries whose source is in the live chain:");
+        for line in &found {
+            println!("  {line}");
+        }
+    }
+
+    println!();
+    match store_origin_of_chain(abs_path, &repo_for_path(abs_path, config)?) {
+        StoreOrigin::SelfRepo(rel) => println!("Origin: this repo ({rel})"),
+        StoreOrigin::Input { name, rel } => println!("Origin: flake input '{name}' ({rel})"),
+        StoreOrigin::Unknown => println!("Origin: generated (no source tree in the chain)"),
+    }
+
+    if let Some(m) = find_mapping_for_path(abs_path)? {
+        println!();
+        match mapping_mismatch(abs_path, &m) {
+            Some(reason) => println!("Target lookup (stale): {reason}"),
+            None => println!(
+                "Target lookup: '{}' ({}) matches the live chain",
+                m.key, m.kind
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+// ── Managed files ────────────────────────────────────────────────────
+
+/// A file managed through the HM or etc mappings.
+struct ManagedFile {
+    path: PathBuf,
+    key: String,
+    kind: &'static str,
+    entry_type: String,
+    repo_relative: Option<String>,
+    defined_in: Vec<String>,
+}
+
+/// Enumerate every file listed in the HM and etc mappings. Directory
+/// entries are expanded to the files below them.
+fn collect_managed_files(config: &Config) -> Result<Vec<ManagedFile>> {
+    let mut files = Vec::new();
+
+    if let Some(mapping) = load_hm_mapping()? {
+        let home = get_home_dir()?;
+        for (key, entry) in &mapping {
+            let target = entry.target.as_deref().unwrap_or(key);
+            let root = home.join(target.strip_prefix("./").unwrap_or(target));
+            let entry_type = entry
+                .entry_type
+                .clone()
+                .unwrap_or_else(|| "generated".to_string());
+
+            let mut paths = Vec::new();
+            collect_files_below(&root, &mut paths);
+            for path in paths {
+                let repo_relative = entry.repo_relative.as_ref().map(|r| {
+                    match path
+                        .strip_prefix(&root)
+                        .ok()
+                        .filter(|s| !s.as_os_str().is_empty())
+                    {
+                        Some(sub) => format!("{r}/{}", sub.display()),
+                        None => r.clone(),
+                    }
+                });
+                files.push(ManagedFile {
+                    path,
+                    key: key.clone(),
+                    kind: "hm",
+                    entry_type: entry_type.clone(),
+                    repo_relative,
+                    defined_in: Vec::new(),
+                });
+            }
+        }
+    }
+
+    if let Some(mapping) = load_etc_mapping()? {
+        let repo = repo_for_path(Path::new("/etc"), config)?;
+        for (key, entry) in &mapping {
+            let Some(p) = &entry.path else { continue };
+            let repo_relative = match entry
+                .source
+                .as_deref()
+                .map(|src| resolve_store_origin(src, &repo))
+            {
+                Some(StoreOrigin::SelfRepo(rel)) => Some(rel),
+                _ => None,
+            };
+            let entry_type = if repo_relative.is_some() {
+                "repo-source"
+            } else {
+                "generated"
+            };
+            // Prefer repo-relative module paths over full store paths.
+            let defined_in = match entry.user_defined_in.as_deref() {
+                Some(user) if !user.is_empty() => user.to_vec(),
+                _ => entry.defined_in.clone().unwrap_or_default(),
+            };
+
+            let mut paths = Vec::new();
+            collect_files_below(Path::new(p), &mut paths);
+            for path in paths {
+                files.push(ManagedFile {
+                    path,
+                    key: key.clone(),
+                    kind: "etc",
+                    entry_type: entry_type.to_string(),
+                    repo_relative: repo_relative.clone(),
+                    defined_in: defined_in.clone(),
+                });
+            }
+        }
+    }
+
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    files.dedup_by(|a, b| a.path == b.path);
+    Ok(files)
+}
+
+/// Push `path` if it is a file, or every file below it if it is a directory.
+/// Symlinked directories are followed, but each directory only once, so a
+/// link back up the tree cannot loop.
+fn collect_files_below(path: &Path, out: &mut Vec<PathBuf>) {
+    fn walk(path: &Path, out: &mut Vec<PathBuf>, visited: &mut HashSet<(u64, u64)>) {
+        let Ok(meta) = fs::metadata(path) else {
+            return;
+        };
+        if meta.is_file() {
+            out.push(path.to_path_buf());
+            return;
+        }
+        if !meta.is_dir() || !visited.insert((meta.dev(), meta.ino())) {
+            return;
+        }
+        let Ok(entries) = fs::read_dir(path) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            walk(&entry.path(), out, visited);
+        }
+    }
+    walk(path, out, &mut HashSet::new());
+}
+
+// ── Grep command ─────────────────────────────────────────────────────
+
+fn cmd_grep(pattern: &str, ignore_case: bool, config: &Config) -> Result<()> {
+    let regex = regex::RegexBuilder::new(pattern)
+        .case_insensitive(ignore_case)
+        .build()
+        .with_context(|| format!("Invalid pattern: {pattern}"))?;
+
+    let overlaid: Vec<PathBuf> = load_all_overlays()
+        .into_iter()
+        .map(|(p, _, _)| key_path(&p))
+        .collect();
+    let mut total = 0;
+
+    for file in collect_managed_files(config)? {
+        let Ok(content) = fs::read(&file.path) else {
+            continue;
+        };
+        let text = String::from_utf8_lossy(&content);
+        let matches: Vec<(usize, &str)> = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| regex.is_match(line))
+            .collect();
+        if matches.is_empty() {
+            continue;
+        }
+
+        let mut provenance = vec![format!("{} {}", file.kind, file.key), file.entry_type];
+        if let Some(rel) = &file.repo_relative {
+            provenance.push(format!("repo {rel}"));
+        }
+        if !file.defined_in.is_empty() {
+            provenance.push(format!("defined in {}", file.defined_in.join(", ")));
+        }
+        if overlaid.contains(&file.path) {
+            provenance.push("overlaid".to_string());
+        }
+
+        println!("{}  [{}]", file.path.display(), provenance.join(" · "));
+        for (idx, line) in matches {
+            println!("  {}: {line}", idx + 1);
+            total += 1;
+        }
+    }
+
+    if total == 0 {
+        eprintln!("No matches in Nix-managed files.");
+    }
+    Ok(())
+}
+
+// ── Ls-managed command ───────────────────────────────────────────────
+
+fn cmd_ls_managed(
+    prefix: Option<&Path>,
+    user_only: bool,
+    system_only: bool,
+    generated_only: bool,
+    paths_only: bool,
+    json: bool,
+    config: &Config,
+) -> Result<()> {
+    let prefix = prefix.map(std::path::absolute).transpose()?;
+    let overlaid: Vec<PathBuf> = load_all_overlays()
+        .into_iter()
+        .map(|(p, _, _)| key_path(&p))
+        .collect();
+
+    let files: Vec<ManagedFile> = collect_managed_files(config)?
+        .into_iter()
+        .filter(|f| !user_only || f.kind == "hm")
+        .filter(|f| !system_only || f.kind == "etc")
+        .filter(|f| !generated_only || f.entry_type == "generated")
+        .filter(|f| prefix.as_ref().is_none_or(|p| f.path.starts_with(p)))
+        .collect();
+    let is_overlaid = |f: &ManagedFile| overlaid.contains(&f.path);
+
+    if paths_only {
+        for f in &files {
+            println!("{}", f.path.display());
+        }
+        return Ok(());
+    }
+
+    if json {
+        let items: Vec<serde_json::Value> = files
+            .iter()
+            .map(|f| {
+                serde_json::json!({
+                    "path": f.path,
+                    "mappingKey": f.key,
+                    "mappingType": f.kind,
+                    "type": f.entry_type,
+                    "repoRelative": f.repo_relative,
+                    "definedIn": f.defined_in,
+                    "overlaid": is_overlaid(f),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&items)?);
+        return Ok(());
+    }
+
+    if files.is_empty() {
+        eprintln!("No managed files found.");
+        return Ok(());
+    }
+
+    println!("{:<60} {:<12} {:<40} STATUS", "PATH", "TYPE", "SOURCE");
+    println!("{}", "-".repeat(122));
+    for f in &files {
+        println!(
+            "{:<60} {:<12} {:<40} {}",
+            f.path.display(),
+            f.entry_type,
+            f.repo_relative.as_deref().unwrap_or("-"),
+            if is_overlaid(f) { "overlaid" } else { "-" }
+        );
+    }
+
+    Ok(())
+}
+
+// ── Config ───────────────────────────────────────────────────────────
+
+/// Settings from the system config, overridden by the user config.
+/// Environment variables and command-line flags take precedence over both.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    /// Editor for overlays, used unless `$NIX_FILE_OVERLAY_EDITOR` is set.
+    editor: Option<String>,
+    repos: RepoConfig,
+    apply: ApplyConfig,
+    defaults: DefaultsConfig,
+    hooks: Vec<HookConfig>,
+    validators: Vec<ValidatorConfig>,
+    policies: Vec<PolicyConfig>,
+}
+
+/// Config repositories that `--apply` edits.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RepoConfig {
+    /// Repository for files in the home directory (default `~/.nixos`).
+    user: Option<String>,
+    /// Repository for system files (default: the user repository).
+    system: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ApplyConfig {
+    /// Command that gets the apply prompt as its argument; an empty string
+    /// only prints guidance. Without it, opencode is used when installed.
+    command: Option<String>,
+}
+
+/// Defaults for options that are otherwise given on the command line.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct DefaultsConfig {
+    persistent: Option<bool>,
+    ttl: Option<String>,
+    hooks: Option<HookMode>,
+    reload: Option<bool>,
+}
+
+impl Config {
+    /// Layer `over` on top of `self`: its settings win, lists are appended.
+    fn merge(mut self, over: Config) -> Config {
+        self.editor = over.editor.or(self.editor);
+        self.repos.user = over.repos.user.or(self.repos.user);
+        self.repos.system = over.repos.system.or(self.repos.system);
+        self.apply.command = over.apply.command.or(self.apply.command);
+        self.defaults.persistent = over.defaults.persistent.or(self.defaults.persistent);
+        self.defaults.ttl = over.defaults.ttl.or(self.defaults.ttl);
+        self.defaults.hooks = over.defaults.hooks.or(self.defaults.hooks);
+        self.defaults.reload = over.defaults.reload.or(self.defaults.reload);
+        self.hooks.extend(over.hooks);
+        self.validators.extend(over.validators);
+        self.policies.extend(over.policies);
+        self
+    }
+}
+
+const SYSTEM_CONFIG_PATH: &str = "/etc/nix-file-overlay/config.toml";
+
+/// Commands to run around changes to overlays whose path matches `path`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HookConfig {
+    /// Glob pattern; `~/` expands to the home directory and `**` crosses
+    /// directories.
+    path: String,
+    /// Events the hook applies to; all of them when empty.
+    #[serde(default)]
+    events: Vec<HookEvent>,
+    /// Run before the change goes live; a failure vetoes the change.
+    #[serde(default)]
+    pre: Vec<String>,
+    /// Run after the change went live.
+    #[serde(default)]
+    post: Vec<String>,
+}
+
+fn get_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join("nix-file-overlay/config.toml"))
+}
+
+fn load_config() -> Result<Config> {
+    let system = read_config_file(Path::new(SYSTEM_CONFIG_PATH))?;
+    let user = match get_config_path() {
+        Some(path) => read_config_file(&path)?,
+        None => Config::default(),
+    };
+    Ok(system.merge(user))
+}
+
+fn read_config_file(path: &Path) -> Result<Config> {
+    if !path.exists() {
+        return Ok(Config::default());
+    }
+    let data = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config at {}", path.display()))?;
+    let config: Config = toml::from_str(&data)
+        .with_context(|| format!("Failed to parse config at {}", path.display()))?;
+    // A mistyped pattern would otherwise match nothing, and a deny rule
+    // silently stop applying.
+    let patterns = config
+        .hooks
+        .iter()
+        .map(|h| &h.path)
+        .chain(config.validators.iter().map(|v| &v.path))
+        .chain(config.policies.iter().map(|p| &p.path));
+    for pattern in patterns {
+        path_pattern(pattern).with_context(|| format!("Invalid config at {}", path.display()))?;
+    }
+    Ok(config)
+}
+
+/// Expand a leading `~/` to the home directory.
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), get_home_dir()) {
+        (Some(rest), Ok(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+const PATH_MATCH_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Compile a config glob pattern.
+fn path_pattern(pattern: &str) -> Result<glob::Pattern> {
+    let expanded = expand_home(pattern).to_string_lossy().into_owned();
+    glob::Pattern::new(&expanded).with_context(|| format!("Invalid path pattern '{pattern}'"))
+}
+
+/// Match `path` against a config glob pattern.
+fn path_matches(pattern: &str, path: &Path) -> bool {
+    path_pattern(pattern).is_ok_and(|p| p.matches_path_with(path, PATH_MATCH_OPTIONS))
+}
+
+// ── Configured hooks ─────────────────────────────────────────────────
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HookEvent {
+    Overlay,
+    Edit,
+    Reset,
+    Remove,
+    Restore,
+}
+
+impl HookEvent {
+    fn as_str(self) -> &'static str {
+        match self {
+            HookEvent::Overlay => "overlay",
+            HookEvent::Edit => "edit",
+            HookEvent::Reset => "reset",
+            HookEvent::Remove => "remove",
+            HookEvent::Restore => "restore",
+        }
+    }
+}
+
+fn matching_hooks<'a>(
+    config: &'a Config,
+    abs_path: &Path,
+    event: HookEvent,
+) -> Vec<&'a HookConfig> {
+    config
+        .hooks
+        .iter()
+        .filter(|h| h.events.is_empty() || h.events.contains(&event))
+        .filter(|h| path_matches(&h.path, abs_path))
+        .collect()
+}
+
+fn hook_command(command: &str, abs_path: &Path, copy: Option<&Path>, event: HookEvent) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", command])
+        .env("NIX_FILE_OVERLAY_PATH", abs_path)
+        .env("NIX_FILE_OVERLAY_EVENT", event.as_str());
+    if let Some(copy) = copy {
+        cmd.env("NIX_FILE_OVERLAY_COPY", copy);
+    }
+    cmd
+}
+
+/// Run the configured pre-hooks for a change to `abs_path`. `candidate`
+/// holds the content that is about to go live; a failing hook vetoes it.
+fn run_pre_hooks(
+    abs_path: &Path,
+    candidate: &Path,
+    event: HookEvent,
+    config: &Config,
+) -> Result<()> {
+    for hook in matching_hooks(config, abs_path, event) {
+        for command in &hook.pre {
+            let status = hook_command(command, abs_path, Some(candidate), event)
+                .status()
+                .with_context(|| format!("Failed to run pre-hook: {command}"))?;
+            if !status.success() {
+                bail!(
+                    "Pre-hook `{command}` failed for {}; change vetoed",
+                    abs_path.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run the configured post-hooks for a change to `abs_path`, asking first
+/// for each one under `--hooks ask`.
+fn run_post_hooks(
+    abs_path: &Path,
+    event: HookEvent,
+    mode: HookMode,
+    config: &Config,
+) -> Result<()> {
+    let hooks = matching_hooks(config, abs_path, event);
+    if mode == HookMode::Never || hooks.is_empty() {
+        return Ok(());
+    }
+
+    let copy = find_overlay_entry(abs_path)
+        .ok()
+        .map(|(e, _)| e.stored_copy);
+    for hook in hooks {
+        for command in &hook.post {
+            if mode == HookMode::Ask && !confirm(&format!("Run post-hook `{command}`?"), true) {
+                continue;
+            }
+            let status = hook_command(command, abs_path, copy.as_deref(), event)
+                .status()
+                .with_context(|| format!("Failed to run post-hook: {command}"))?;
+            if !status.success() {
+                eprintln!("Warning: post-hook `{command}` exited with non-zero status");
+            }
+        }
+    }
+    Ok(())
+}
+
+// ── Validators ───────────────────────────────────────────────────────
+
+/// External checkers run on the edited copy before it goes live. `{}` is
+/// replaced by the file to check; a checker is skipped when its command is
+/// not installed.
+const EXTERNAL_VALIDATORS: &[(&str, &str)] = &[
+    ("/etc/sudoers", "visudo -cf {}"),
+    ("/etc/sudoers.d/*", "visudo -cf {}"),
+    ("/etc/ssh/sshd_config", "sudo sshd -t -f {}"),
+    ("~/.config/niri/config.kdl", "niri validate -c {}"),
+    ("/etc/nginx/nginx.conf", "sudo nginx -t -c {}"),
+];
+
+/// A user-declared validator.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ValidatorConfig {
+    /// Glob pattern, as for hooks.
+    path: String,
+    /// Shell command; `{}` is replaced by the file to check.
+    command: String,
+}
+
+/// Check `candidate`, the content about to go live at `abs_path`, with the
+/// built-in parser for its format and every matching external checker.
+fn validate(abs_path: &Path, candidate: &Path, config: &Config) -> Result<()> {
+    let mut errors = Vec::new();
+
+    if let Err(e) = validate_format(abs_path, candidate) {
+        errors.push(e);
+    }
+
+    let configured = config
+        .validators
+        .iter()
+        .map(|v| (v.path.as_str(), v.command.as_str()));
+    for (pattern, command) in EXTERNAL_VALIDATORS.iter().copied().chain(configured) {
+        if !path_matches(pattern, abs_path) {
+            continue;
+        }
+        let program = command
+            .split_whitespace()
+            .find(|w| *w != "sudo")
+            .unwrap_or_default();
+        if !command_exists(program) {
+            continue;
+        }
+        let command = command.replace("{}", &shell_quote(&candidate.to_string_lossy()));
+        let output = Command::new("sh")
+            .args(["-c", &command])
+            .output()
+            .with_context(|| format!("Failed to run validator: {command}"))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let detail = if stderr.trim().is_empty() {
+                stdout
+            } else {
+                stderr
+            };
+            errors.push(format!("{command}: {}", detail.trim()));
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!(
+            "Validation failed for {}:\n  {}",
+            abs_path.display(),
+            errors.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+/// Parse `candidate` according to the file extension of `abs_path`. KDL
+/// only gets a balance check.
+fn validate_format(abs_path: &Path, candidate: &Path) -> Result<(), String> {
+    let Some(ext) = abs_path.extension().and_then(|e| e.to_str()) else {
+        return Ok(());
+    };
+    if !["json", "toml", "yaml", "yml", "kdl"].contains(&ext) {
+        return Ok(());
+    }
+    let text = fs::read_to_string(candidate).map_err(|e| format!("{ext}: {e}"))?;
+
+    match ext {
+        "json" => serde_json::from_str::<serde_json::Value>(&text)
+            .map(drop)
+            .map_err(|e| format!("JSON: {e}")),
+        "toml" => toml::from_str::<toml::Table>(&text)
+            .map(drop)
+            .map_err(|e| format!("TOML: {e}")),
+        "yaml" | "yml" => yaml_rust2::YamlLoader::load_from_str(&text)
+            .map(drop)
+            .map_err(|e| format!("YAML: {e}")),
+        _ => check_kdl_balance(&text).map_err(|e| format!("KDL: {e}")),
+    }
+}
+
+/// Brace and string balance check for KDL, not a full parse: strings, raw
+/// strings and comments must be terminated and braces balance. That
+/// catches the typos that break a whole config; program-specific checkers
+/// such as `niri validate` go further.
+fn check_kdl_balance(text: &str) -> Result<(), String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut line = 1;
+    let mut open_braces: Vec<usize> = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\n' => line += 1,
+            '/' if chars.get(i + 1) == Some(&'/') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                let start = line;
+                let mut depth = 1;
+                i += 2;
+                while depth > 0 {
+                    match (chars.get(i), chars.get(i + 1)) {
+                        (None, _) => return Err(format!("unterminated comment from line {start}")),
+                        (Some('/'), Some('*')) => (depth, i) = (depth + 1, i + 1),
+                        (Some('*'), Some('/')) => (depth, i) = (depth - 1, i + 1),
+                        (Some('\n'), _) => line += 1,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                continue;
+            }
+            '"' => {
+                let start = line;
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(format!("unterminated string from line {start}")),
+                        Some('\\') => i += 1,
+                        Some('"') => break,
+                        Some('\n') => line += 1,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+            }
+            'r' if matches!(chars.get(i + 1), Some('"' | '#'))
+                && (i == 0 || !chars[i - 1].is_alphanumeric()) =>
+            {
+                let start = line;
+                let hashes = chars[i + 1..].iter().take_while(|c| **c == '#').count();
+                i += 1 + hashes;
+                if chars.get(i) != Some(&'"') {
+                    return Err(format!("malformed raw string on line {start}"));
+                }
+                let close: String = std::iter::once('"')
+                    .chain(std::iter::repeat_n('#', hashes))
+                    .collect();
+                let rest: String = chars[i + 1..].iter().collect();
+                let Some(end) = rest.find(&close) else {
+                    return Err(format!("unterminated raw string from line {start}"));
+                };
+                let body = &rest[..end];
+                line += body.matches('\n').count();
+                i += 1 + body.chars().count() + close.chars().count();
+                continue;
+            }
+            '{' => open_braces.push(line),
+            '}' if open_braces.pop().is_none() => {
+                return Err(format!("unexpected '}}' on line {line}"));
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    match open_braces.pop() {
+        Some(start) => Err(format!("'{{' on line {start} is never closed")),
+        None => Ok(()),
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+// ── Policies ─────────────────────────────────────────────────────────
+
+/// A rule for overlays whose path matches `path`. Every matching rule
+/// applies, so the user config can add rules but not lift system ones.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyConfig {
+    /// Glob pattern, as for hooks.
+    path: String,
+    action: PolicyAction,
+    /// Longest lifetime for the `max-ttl` action, e.g. "1h".
+    max_ttl: Option<String>,
+    /// Shown when the rule refuses or asks.
+    reason: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum PolicyAction {
+    /// Never overlay or edit the file.
+    Deny,
+    /// Ask before every change.
+    Confirm,
+    /// Overlays always go through `try`.
+    Try,
+    /// Overlays are persistent unless `--temporary` is given.
+    Persistent,
+    /// Overlays expire after `max_ttl` at the latest.
+    MaxTtl,
+}
+
+/// What the rules matching one path add up to.
+#[derive(Default)]
+struct Policy {
+    deny: Option<String>,
+    confirm: Vec<String>,
+    force_try: bool,
+    persistent: bool,
+    max_ttl: Option<(chrono::Duration, String)>,
+}
+
+fn policy_for(abs_path: &Path, config: &Config) -> Result<Policy> {
+    resolve_policy(&config.policies, abs_path)
+}
+
+fn resolve_policy(rules: &[PolicyConfig], abs_path: &Path) -> Result<Policy> {
+    let chain = symlink_chain(abs_path);
+    let target = fs::canonicalize(abs_path).ok();
+    let mut policy = Policy::default();
+    for rule in rules {
+        if !policy_applies(&rule.path, &chain, target.as_deref())? {
+            continue;
+        }
+        let reason = rule
+            .reason
+            .clone()
+            .unwrap_or_else(|| format!("matches policy {}", rule.path));
+        match rule.action {
+            PolicyAction::Deny => {
+                policy.deny.get_or_insert(reason);
+            }
+            PolicyAction::Confirm => policy.confirm.push(reason),
+            PolicyAction::Try => policy.force_try = true,
+            PolicyAction::Persistent => policy.persistent = true,
+            PolicyAction::MaxTtl => {
+                let raw = rule.max_ttl.as_deref().with_context(|| {
+                    format!("Policy for {} is max-ttl but sets no max_ttl", rule.path)
+                })?;
+                let ttl = parse_duration(raw).map_err(|e| {
+                    anyhow::anyhow!("Invalid max_ttl in policy for {}: {e}", rule.path)
+                })?;
+                if policy.max_ttl.as_ref().is_none_or(|(max, _)| ttl < *max) {
+                    policy.max_ttl = Some((ttl, raw.to_string()));
+                }
+            }
+        }
+    }
+    Ok(policy)
+}
+
+/// Whether the rule for `pattern` covers a path with the symlink `chain` and
+/// canonical `target`. Every hop counts, and so does any file the pattern
+/// names that resolves to the same target: both `/etc/./ssh/sshd_config`
+/// and `/etc/static/ssh/sshd_config` mount over the file a rule for
+/// `/etc/ssh/sshd_config` protects.
+fn policy_applies(pattern: &str, chain: &[PathBuf], target: Option<&Path>) -> Result<bool> {
+    let compiled = path_pattern(pattern)?;
+    if chain
+        .iter()
+        .map(PathBuf::as_path)
+        .chain(target)
+        .any(|p| compiled.matches_path_with(p, PATH_MATCH_OPTIONS))
+    {
+        return Ok(true);
+    }
+    let Some(target) = target else {
+        return Ok(false);
+    };
+    let named = glob::glob_with(compiled.as_str(), PATH_MATCH_OPTIONS)
+        .with_context(|| format!("Invalid path pattern '{pattern}'"))?;
+    Ok(named
+        .flatten()
+        .any(|p| fs::canonicalize(p).is_ok_and(|p| p == target)))
+}
+
+impl Policy {
+    /// Refuse denied paths and ask before changing confirm-only ones.
+    fn check(&self, abs_path: &Path, verb: &str) -> Result<()> {
+        if let Some(reason) = &self.deny {
+            bail!("Policy forbids changing {} ({reason})", abs_path.display());
+        }
+        if self.confirm.is_empty() {
+            return Ok(());
+        }
+        let question = format!(
+            "{} {}. {verb} it anyway?",
+            abs_path.display(),
+            self.confirm.join("; ")
+        );
+        if !confirm(&question, false) {
+            bail!(
+                "Policy requires confirmation to change {}",
+                abs_path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Cap the lifetime of a new overlay at the policy's maximum.
+    fn limit_ttl(&self, ttl: Option<chrono::Duration>) -> Option<chrono::Duration> {
+        let Some((max, raw)) = &self.max_ttl else {
+            return ttl;
+        };
+        if ttl.is_none_or(|ttl| ttl > *max) {
+            eprintln!("Policy limits this overlay to {raw}.");
+            return Some(*max);
+        }
+        ttl
+    }
+}
+
+// ── Staged editing ───────────────────────────────────────────────────
+
+/// Where the editable copy of an overlay lives while it is being edited.
+/// The file keeps its original name so editors pick the right syntax.
+fn staging_path_for(abs_path: &Path) -> Result<PathBuf> {
+    let name = abs_path
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_else(|| "file".into());
+    Ok(private_tmp_dir()?
+        .join("staging")
+        .join(path_hash(abs_path))
+        .join(name))
+}
+
+/// Check content that is about to replace what is live at `abs_path`.
+fn check_change(
+    abs_path: &Path,
+    candidate: &Path,
+    event: HookEvent,
+    config: &Config,
+) -> Result<()> {
+    // Removing an overlay brings back the Nix-managed original as is.
+    if event != HookEvent::Remove {
+        validate(abs_path, candidate, config)?;
+    }
+    run_pre_hooks(abs_path, candidate, event, config)
+}
+
+/// Edit `stored_copy` through a staging copy and sync it back only once the
+/// result passed the checks. A rejected edit stays in staging and is picked
+/// up again by the next edit. Returns whether the content changed.
+fn edit_staged(
+    abs_path: &Path,
+    stored_copy: &Path,
+    event: HookEvent,
+    config: &Config,
+) -> Result<bool> {
+    let staging = staging_path_for(abs_path)?;
+    let current = fs::read(stored_copy)
+        .with_context(|| format!("Failed to read {}", stored_copy.display()))?;
+
+    if staging.exists() {
+        eprintln!("Resuming rejected edit from {}", staging.display());
+    } else {
+        if let Some(parent) = staging.parent() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+        write_new_file(&staging, &current, 0o600)?;
+    }
+
+    open_editor(&staging, config)?;
+    let edited = fs::read(&staging)?;
+
+    if edited == current && event != HookEvent::Overlay {
+        discard_staging(&staging);
+        return Ok(false);
+    }
+
+    check_change(abs_path, &staging, event, config)
+        .with_context(|| format!("Edited content kept at {}", staging.display()))?;
+
+    // Write in place: the bind mount refers to the stored copy's inode.
+    storage_write(stored_copy, &edited, true)
+        .with_context(|| format!("Failed to write stored copy {}", stored_copy.display()))?;
+    discard_staging(&staging);
+    Ok(true)
+}
+
+/// Check `content` from a user-owned staging copy, so validators and hooks
+/// can read it wherever the overlay is stored.
+fn check_staged(abs_path: &Path, content: &[u8], event: HookEvent, config: &Config) -> Result<()> {
+    let staging = staging_path_for(abs_path)?;
+    if let Some(parent) = staging.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+    }
+    replace_file(&staging, content, 0o600)?;
+    let checked = check_change(abs_path, &staging, event, config);
+    discard_staging(&staging);
+    checked
+}
+
+fn discard_staging(staging: &Path) {
+    fs::remove_file(staging).ok();
+    if let Some(parent) = staging.parent() {
+        fs::remove_dir(parent).ok();
+    }
+}
+
+// ── Overlay command ──────────────────────────────────────────────────
+
+fn cmd_overlay(
+    path: &Path,
+    options: OverlayOptions,
+    post: PostChange,
+    config: &Config,
+) -> Result<()> {
+    let abs_path = create_overlay(path, options, config)?;
+    after_change(&abs_path, HookEvent::Overlay, post, config)
+}
+
+/// Copy, edit and mount an overlay and register it; returns its path.
+fn create_overlay(path: &Path, options: OverlayOptions, config: &Config) -> Result<PathBuf> {
+    let OverlayOptions {
+        persistent,
+        no_edit,
+        ..
+    } = options;
+    let abs_path = resolve_path(path)?;
+
+    if !abs_path.is_symlink() {
+        bail!(
+            "Only Nix-managed files (symlinks) can be overlaid. \
+             {} is not a symlink.",
+            abs_path.display()
+        );
+    }
+
+    if !abs_path.is_file() {
+        bail!(
+            "Symlink does not point to a regular file: {}",
+            abs_path.display()
+        );
+    }
+
+    if is_bind_mounted(&abs_path)? {
+        bail!("Path is already overlaid: {}", abs_path.display());
+    }
+
+    options.policy.check(&abs_path, "Overlay")?;
+    let expires_at = options
+        .ttl
+        .map(|ttl| {
+            Utc::now()
+                .checked_add_signed(ttl)
+                .context("TTL is too long")
+        })
+        .transpose()?;
+
+    let original_target = get_symlink_target(&abs_path);
+    let base_path = fs::canonicalize(&abs_path).ok();
+
+    // Read content through the symlink
+    let content = fs::read(&abs_path)
+        .with_context(|| format!("Failed to read file: {}", abs_path.display()))?;
+
+    // The base stays the current original, so diffs show the rollback.
+    let generation = options
+        .from_generation
+        .map(|number| generation_source(&abs_path, number))
+        .transpose()?;
+    let initial = match &generation {
+        Some(g) => {
+            let old = fs::read(&g.source)
+                .with_context(|| format!("Failed to read {}", g.source.display()))?;
+            if old == content {
+                eprintln!(
+                    "Note: {} is unchanged since {} generation {}",
+                    abs_path.display(),
+                    g.profile,
+                    g.number
+                );
+            }
+            old
+        }
+        None => content.clone(),
+    };
+
+    let storage = storage_dir_for(&abs_path, persistent)?;
+    let overlay_dir = overlay_dir_for(&storage, &abs_path);
+    let path_file = overlay_dir.join("path");
+    storage_write(&path_file, abs_path.as_os_str().as_bytes(), false)?;
+
+    let stored_copy = overlay_dir.join("content");
+    storage_write(&stored_copy, &initial, false)?;
+
+    // The bind mount covers the store file itself, so the original can't be
+    // read back through the symlink chain later on.
+    let base_copy = overlay_dir.join("base");
+    storage_write(&base_copy, &content, false)?;
+
+    let discard_copies = || {
+        storage_remove(&stored_copy).ok();
+        storage_remove(&base_copy).ok();
+        storage_remove(&path_file).ok();
+    };
+
+    let (mapping_info, _) = lookup_mapping_checked(&abs_path)?;
+
+    let checked = if no_edit {
+        check_staged(&abs_path, &initial, HookEvent::Overlay, config)
+    } else {
+        edit_staged(&abs_path, &stored_copy, HookEvent::Overlay, config).map(|_| ())
+    };
+    if let Err(e) = checked {
+        discard_copies();
+        return Err(e);
+    }
+
+    // Daemons such as sshd or sudo check the mode and owner of their files.
+    let attrs = overlay_attrs(&abs_path, &options)?;
+    if let Err(e) = storage_set_attrs(&stored_copy, attrs) {
+        discard_copies();
+        return Err(e);
+    }
+
+    // Nothing may fail between the mount and saving the registry, or the
+    // overlay would be left without an entry.
+    let reg_path = registry_path_for(&abs_path, persistent)?;
+    let mut registry = match load_registry(&reg_path) {
+        Ok(registry) => registry,
+        Err(e) => {
+            discard_copies();
+            return Err(e);
+        }
+    };
+
+    // Bind mount the editable copy on top of the symlink path
+    if let Err(e) = run_sudo_mount(&stored_copy, &abs_path, &[attrs.uid]) {
+        discard_copies();
+        return Err(e);
+    }
+
+    registry.insert(
+        path_key(&abs_path),
+        OverlayEntry {
+            stored_copy: stored_copy.clone(),
+            original_target,
+            persistent,
+            created_at: Utc::now().to_rfc3339(),
+            mapping_key: mapping_info.as_ref().map(|m| m.key.clone()),
+            mapping_type: mapping_info.map(|m| m.kind),
+            base_copy: Some(base_copy.clone()),
+            try_unit: None,
+            try_deadline: None,
+            expires_at: expires_at.map(|t| t.to_rfc3339()),
+            until_logout: options.until_logout,
+            boot_id: current_boot_id(),
+            mode: Some(attrs.mode),
+            uid: Some(attrs.uid),
+            gid: Some(attrs.gid),
+            base_path,
+            generation: generation.clone(),
+        },
+    );
+    // An overlay without a registry entry could not be listed or removed.
+    if let Err(e) = save_registry(&reg_path, &registry) {
+        run_sudo_umount(&abs_path).ok();
+        discard_copies();
+        return Err(e);
+    }
+
+    eprintln!(
+        "Overlaid {}{} ({})",
+        abs_path.display(),
+        generation
+            .map(|g| format!(
+                " with its content from {} generation {}",
+                g.profile, g.number
+            ))
+            .unwrap_or_default(),
+        if persistent {
+            "persistent"
+        } else {
+            "temporary"
+        },
+    );
+
+    Ok(abs_path)
+}
+
+/// Where `abs_path` came from in generation `number` of the system profile
+/// (for /etc) or the Home-Manager profile (for the home directory).
+fn generation_source(abs_path: &Path, number: u64) -> Result<GenerationRef> {
+    let home = get_home_dir()?;
+    let (profile, profile_dirs, rel) = if let Ok(rel) = abs_path.strip_prefix(&home) {
+        let user = std::env::var("USER").unwrap_or_default();
+        (
+            "home-manager",
+            vec![
+                home.join(".local/state/nix/profiles"),
+                PathBuf::from(format!("/nix/var/nix/profiles/per-user/{user}")),
+            ],
+            Path::new("home-files").join(rel),
+        )
+    } else if let Ok(rel) = abs_path.strip_prefix("/etc") {
+        (
+            "system",
+            vec![PathBuf::from("/nix/var/nix/profiles")],
+            Path::new("etc").join(rel),
+        )
+    } else {
+        bail!(
+            "Only /etc and home directory files have generations, not {}",
+            abs_path.display()
+        );
+    };
+
+    let link_name = format!("{profile}-{number}-link");
+    let Some(link) = profile_dirs
+        .iter()
+        .map(|dir| dir.join(&link_name))
+        .find(|link| link.exists())
+    else {
+        bail!("Generation {number} of the {profile} profile does not exist (any more)");
+    };
+    let source = fs::canonicalize(link.join(&rel))
+        .ok()
+        .filter(|source| source.is_file())
+        .with_context(|| {
+            format!(
+                "{} is not a file in {profile} generation {number}",
+                abs_path.display()
+            )
+        })?;
+    Ok(GenerationRef {
+        profile: profile.to_string(),
+        number,
+        source,
+    })
+}
+
+fn open_editor(path: &Path, config: &Config) -> Result<()> {
+    let editor = std::env::var("NIX_FILE_OVERLAY_EDITOR")
+        .ok()
+        .or(config.editor.clone())
+        .or_else(|| std::env::var("EDITOR").ok())
+        .unwrap_or_else(|| "vi".to_string());
+    let status = Command::new(&editor)
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to launch editor: {editor}"))?;
+    if !status.success() {
+        eprintln!("Editor exited with non-zero status");
+    }
+    Ok(())
+}
+
+// ── Edit / reset commands ────────────────────────────────────────────
+
+fn cmd_edit(path: &Path, post: PostChange, config: &Config) -> Result<()> {
+    let abs_path = resolve_path(path)?;
+    let (entry, _) = find_overlay_entry(&abs_path)?;
+    policy_for(&abs_path, config)?.check(&abs_path, "Edit")?;
+    if is_dropin_entry(&entry) {
+        return edit_dropin(&abs_path, &entry, HookEvent::Edit, config);
+    }
+    if !edit_staged(&abs_path, &entry.stored_copy, HookEvent::Edit, config)? {
+        eprintln!("No changes.");
+        return Ok(());
+    }
+    after_change(&abs_path, HookEvent::Edit, post, config)
+}
+
+fn cmd_reset(path: &Path, post: PostChange, config: &Config) -> Result<()> {
+    let abs_path = resolve_path(path)?;
+    let (mut entry, reg_path) = find_overlay_entry(&abs_path)?;
+    if is_dropin_entry(&entry) {
+        bail!("Drop-ins have no original content; remove it with -r instead.");
+    }
+    policy_for(&abs_path, config)?.check(&abs_path, "Reset")?;
+    let original = read_original_content(&entry).with_context(|| {
+        format!(
+            "Original content of {} is not available",
+            abs_path.display()
+        )
+    })?;
+
+    check_staged(&abs_path, &original, HookEvent::Reset, config)?;
+
+    // Write in place: the bind mount refers to the stored copy's inode.
+    storage_write(&entry.stored_copy, &original, true).with_context(|| {
+        format!(
+            "Failed to write stored copy {}",
+            entry.stored_copy.display()
+        )
+    })?;
+    match entry.generation.take() {
+        Some(g) => {
+            let mut registry = load_registry(&reg_path)?;
+            registry.insert(path_key(&abs_path), entry);
+            save_registry(&reg_path, &registry)?;
+            eprintln!(
+                "Reset overlay for {} from {} generation {} to the current original",
+                abs_path.display(),
+                g.profile,
+                g.number
+            );
+        }
+        None => eprintln!("Reset overlay for {} to the original", abs_path.display()),
+    }
+
+    after_change(&abs_path, HookEvent::Reset, post, config)
+}
+
+// ── Change hooks ─────────────────────────────────────────────────────
+
+/// Let programs pick up an overlay's new content.
+fn after_change(
+    abs_path: &Path,
+    event: HookEvent,
+    post: PostChange,
+    config: &Config,
+) -> Result<()> {
+    run_change_hooks(abs_path, post.hooks)?;
+    run_post_hooks(abs_path, event, post.hooks, config)?;
+    reload_affected_units(abs_path, post.reload_units)
+}
+
+/// Shell functions Home-Manager provides to `onChange` scripts during
+/// activation.
+const HM_HOOK_PRELUDE: &str = r#"
+run() { case "$1" in --quiet|--silence) shift ;; esac; "$@"; }
+verboseEcho() { :; }
+noteEcho() { echo "$@"; }
+warnEcho() { echo "$@" >&2; }
+errorEcho() { echo "$@" >&2; }
+"#;
+
+/// Offer to run the Home-Manager `onChange` hook of the entry managing
+/// `abs_path`, so the program picks up the new content.
+fn run_change_hooks(abs_path: &Path, mode: HookMode) -> Result<()> {
+    if mode == HookMode::Never {
+        return Ok(());
+    }
+    let Some(m) = find_mapping_for_path(abs_path)? else {
+        return Ok(());
+    };
+    let Some(script) = m.on_change.as_deref().filter(|s| !s.trim().is_empty()) else {
+        return Ok(());
+    };
+
+    if mode == HookMode::Ask && !confirm(&format!("Run the onChange hook of '{}'?", m.key), true) {
+        return Ok(());
+    }
+
+    eprintln!("Running onChange hook of '{}'...", m.key);
+    let status = Command::new("bash")
+        .args(["-c", &format!("{HM_HOOK_PRELUDE}\n{script}")])
+        .env("DRY_RUN_CMD", "")
+        .env("VERBOSE_ARG", "")
+        .current_dir(get_home_dir()?)
+        .status()
+        .context("Failed to run onChange hook")?;
+    if !status.success() {
+        eprintln!("onChange hook exited with non-zero status");
+    }
+    Ok(())
+}
+
+/// A system unit whose configuration references an overlaid /etc file.
+struct AffectedUnit {
+    name: String,
+    /// Referenced through `reloadTriggers`, so a reload is enough.
+    reload: bool,
+}
+
+const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
+
+/// Find the system units whose `restartTriggers`/`reloadTriggers` or
+/// `Exec*=` lines reference `abs_path`, its etc source or its store path.
+fn find_affected_units(abs_path: &Path) -> Vec<AffectedUnit> {
+    let mut needles: Vec<String> = symlink_chain(abs_path)
+        .iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .filter(|p| p.starts_with("/nix/store/") || p.starts_with("/etc/"))
+        .collect();
+    if let Ok(Some(mapping)) = load_etc_mapping() {
+        needles.extend(
+            mapping
+                .values()
+                .filter(|e| e.path.as_deref().map(Path::new) == Some(abs_path))
+                .filter_map(|e| e.source.clone()),
+        );
+    }
+    let references = |value: &str| needles.iter().any(|n| mentions_path(value, n));
+
+    let Ok(entries) = fs::read_dir(SYSTEM_UNIT_DIR) else {
+        return Vec::new();
+    };
+    let mut units: Vec<AffectedUnit> = Vec::new();
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let (unit, files) = if let Some(unit) = name.strip_suffix(".d") {
+            let mut files = Vec::new();
+            collect_files_below(&entry.path(), &mut files);
+            (unit.to_string(), files)
+        } else {
+            (name, vec![entry.path()])
+        };
+        if !unit.ends_with(".service") {
+            continue;
+        }
+
+        for file in files {
+            let Ok(content) = fs::read_to_string(&file) else {
+                continue;
+            };
+            let Some(reload) = unit_file_reference(&content, &references) else {
+                continue;
+            };
+            match units.iter_mut().find(|u| u.name == unit) {
+                Some(u) => u.reload &= reload,
+                None => units.push(AffectedUnit {
+                    name: unit.clone(),
+                    reload,
+                }),
+            }
+        }
+    }
+
+    units.retain(|u| {
+        Command::new("systemctl")
+            .args(["is-active", "--quiet", &u.name])
+            .status()
+            .is_ok_and(|s| s.success())
+    });
+    units.sort_by(|a, b| a.name.cmp(&b.name));
+    units
+}
+
+/// How a unit file refers to a path `references` accepts: `Some(true)` when
+/// only through reload triggers, `Some(false)` when a restart is needed.
+fn unit_file_reference(content: &str, references: &dyn Fn(&str) -> bool) -> Option<bool> {
+    let mut found: Option<bool> = None;
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let (reload, value) = match key.trim() {
+            "X-Reload-Triggers" => (true, trigger_values(value)),
+            "X-Restart-Triggers" => (false, trigger_values(value)),
+            k if k.starts_with("Exec") => (false, value.to_string()),
+            _ => continue,
+        };
+        if references(&value) {
+            found = Some(found.unwrap_or(true) && reload);
+        }
+    }
+    found
+}
+
+/// NixOS writes `restartTriggers`/`reloadTriggers` to a store file and puts
+/// that file's path in the unit, so read the triggers from there.
+fn trigger_values(value: &str) -> String {
+    value
+        .split_whitespace()
+        .map(|token| {
+            let is_trigger_file = Path::new(token)
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| {
+                    n.contains("-X-Restart-Triggers-") || n.contains("-X-Reload-Triggers-")
+                });
+            is_trigger_file
+                .then(|| fs::read_to_string(token).ok())
+                .flatten()
+                .unwrap_or_else(|| token.to_string())
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether `value` contains `path` as a whole path, not merely as a prefix
+/// of a longer one (`/etc/foo` must not match `/etc/foo.d`).
+fn mentions_path(value: &str, path: &str) -> bool {
+    value.match_indices(path).any(|(i, _)| {
+        !value[i + path.len()..].starts_with(|c: char| c.is_alphanumeric() || "._-".contains(c))
+    })
+}
+
+/// List the running units affected by a change to `abs_path` and offer to
+/// reload or restart them.
+fn reload_affected_units(abs_path: &Path, auto: bool) -> Result<()> {
+    if !abs_path.starts_with("/etc/") {
+        return Ok(());
+    }
+    let units = find_affected_units(abs_path);
+    if units.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!("Running units using {}:", abs_path.display());
+    for u in &units {
+        let action = if u.reload { "reload" } else { "restart" };
+        eprintln!("  {} ({action})", u.name);
+    }
+    if !auto && !confirm("Reload/restart them now?", false) {
+        eprintln!("  Pass --reload to do this without asking.");
+        return Ok(());
+    }
+
+    for u in &units {
+        let action = if u.reload { "reload" } else { "restart" };
+        let status = Command::new("sudo")
+            .args(["systemctl", action, &u.name])
+            .status()
+            .with_context(|| format!("Failed to {action} {}", u.name))?;
+        if !status.success() {
+            eprintln!("Warning: systemctl {action} {} failed", u.name);
+        }
+    }
+    Ok(())
+}
+
+/// Ask a yes/no question on the terminal. Returns `default` when the answer
+/// is empty, and `false` when stdin is not a terminal.
+fn confirm(question: &str, default: bool) -> bool {
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+    let hint = if default { "[Y/n]" } else { "[y/N]" };
+    eprint!("{question} {hint} ");
+    std::io::stderr().flush().ok();
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    match answer.trim().to_lowercase().as_str() {
+        "" => default,
+        a => a == "y" || a == "yes",
+    }
+}
+
+// ── Try / confirm commands ───────────────────────────────────────────
+
+/// Default time a tried overlay has before it is reverted.
+const TRY_TIMEOUT_SECS: u64 = 120;
+
+/// Name of the transient systemd unit that reverts a tried overlay.
+fn try_unit_name(abs_path: &Path) -> String {
+    let digest = Sha256::digest(abs_path.as_os_str().as_encoded_bytes());
+    let hex: String = digest[..6].iter().map(|b| format!("{b:02x}")).collect();
+    format!("nix-file-overlay-try-{hex}")
+}
+
+fn cmd_try(
+    path: &Path,
+    timeout: u64,
+    from_generation: Option<u64>,
+    policy: Policy,
+    post: PostChange,
+    config: &Config,
+) -> Result<()> {
+    let abs_path = resolve_path(path)?;
+    if find_overlay_entry(&abs_path).is_ok() {
+        bail!(
+            "{} is already overlaid; remove it first to try a change",
+            abs_path.display()
+        );
+    }
+
+    let deadline = i64::try_from(timeout)
+        .ok()
+        .and_then(chrono::Duration::try_seconds)
+        .and_then(|timeout| Utc::now().checked_add_signed(timeout))
+        .with_context(|| format!("Timeout of {timeout}s is too long"))?;
+
+    let options = OverlayOptions {
+        ttl: policy.limit_ttl(None),
+        from_generation,
+        policy,
+        ..Default::default()
+    };
+    // Hooks and unit reloads only run once the revert is scheduled.
+    create_overlay(&abs_path, options, config)?;
+    let (mut entry, reg_path) = find_overlay_entry(&abs_path)?;
+
+    // The revert runs as root from a system timer, so it still fires when the
+    // change cut off our own session (sshd, PAM, networking).
+    let unit = try_unit_name(&abs_path);
+    let exe = std::env::current_exe().context("Failed to locate our executable")?;
+    let status = Command::new("sudo")
+        .arg("systemd-run")
+        .arg(format!("--unit={unit}"))
+        .arg(format!("--on-active={timeout}"))
+        .args(["--timer-property=AccuracySec=1s", "--collect", "--quiet"])
+        .arg(&exe)
+        .arg("--registry")
+        .arg(&reg_path)
+        .args(["--hooks", "never", "--reload", "--remove"])
+        .arg(&abs_path)
+        .status()
+        .context("Failed to execute sudo systemd-run")?;
+    if !status.success() {
+        eprintln!("Could not schedule the automatic revert; removing the overlay.");
+        cmd_remove(&abs_path, Some(&reg_path), config)?;
+        bail!("sudo systemd-run failed");
+    }
+
+    entry.try_unit = Some(unit);
+    entry.try_deadline = Some(deadline.to_rfc3339());
+    let mut registry = load_registry(&reg_path)?;
+    registry.insert(path_key(&abs_path), entry);
+    save_registry(&reg_path, &registry)?;
+
+    eprintln!(
+        "\nTrying {}. It will be reverted in {timeout}s unless you run:\n  nix-file-overlay confirm {}",
+        abs_path.display(),
+        abs_path.display()
+    );
+    after_change(&abs_path, HookEvent::Overlay, post, config)
+}
+
+fn cmd_confirm(path: Option<&Path>) -> Result<()> {
+    let target = path.map(std::path::absolute).transpose()?;
+    let mut confirmed = 0;
+
+    for (path_str, entry, _) in load_all_overlays() {
+        let Some(unit) = &entry.try_unit else {
+            continue;
+        };
+        let abs_path = key_path(&path_str);
+        if target.as_ref().is_some_and(|t| *t != abs_path) {
+            continue;
+        }
+
+        let status = Command::new("sudo")
+            .args(["systemctl", "stop"])
+            .arg(format!("{unit}.timer"))
+            .status()
+            .context("Failed to execute sudo systemctl")?;
+        if !status.success() {
+            eprintln!("Warning: could not stop {unit}.timer; it may already have fired");
+            continue;
+        }
+
+        let (mut entry, reg_path) = find_overlay_entry(&abs_path)?;
+        entry.try_unit = None;
+        entry.try_deadline = None;
+        let mut registry = load_registry(&reg_path)?;
+        registry.insert(path_str, entry);
+        save_registry(&reg_path, &registry)?;
+
+        eprintln!("Confirmed overlay for {}", abs_path.display());
+        confirmed += 1;
+    }
+
+    if confirmed == 0 {
+        match target {
+            Some(t) => bail!("No pending try for {}", t.display()),
+            None => bail!("No pending tries"),
+        }
+    }
+    Ok(())
+}
+
+// ── Unit drop-in command ─────────────────────────────────────────────
+
+/// `mapping_type` of registry entries that track a systemd drop-in.
+const DROPIN_MAPPING_TYPE: &str = "systemd-dropin";
+
+const DROPIN_FILE_NAME: &str = "90-nix-file-overlay.conf";
+
+/// Full unit name, defaulting to a `.service` unit.
+fn normalize_unit_name(name: &str) -> String {
+    if name.contains('.') {
+        name.to_string()
+    } else {
+        format!("{name}.service")
+    }
+}
+
+fn dropin_path_for(unit: &str, user: bool) -> Result<PathBuf> {
+    let dir = if user {
+        let runtime = std::env::var("XDG_RUNTIME_DIR")
+            .context("XDG_RUNTIME_DIR is not set; needed for user unit drop-ins")?;
+        PathBuf::from(runtime).join("systemd/user")
+    } else {
+        PathBuf::from("/run/systemd/system")
+    };
+    Ok(dir.join(format!("{unit}.d")).join(DROPIN_FILE_NAME))
+}
+
+fn is_dropin_entry(entry: &OverlayEntry) -> bool {
+    entry.mapping_type.as_deref() == Some(DROPIN_MAPPING_TYPE)
+}
+
+fn is_user_dropin(dropin: &Path) -> bool {
+    !dropin.starts_with("/run/systemd/system")
+}
+
+/// Whether `path` is one of our drop-ins for a system unit.
+fn is_system_dropin(path: &Path) -> bool {
+    let unit_dir = path.parent();
+    path.file_name() == Some(DROPIN_FILE_NAME.as_ref())
+        && unit_dir.and_then(|d| d.parent()) == Some(Path::new("/run/systemd/system"))
+        && unit_dir
+            .and_then(|d| d.file_name())
+            .is_some_and(|n| n.as_bytes().ends_with(b".d") && n.as_bytes() != b".d")
+}
+
+fn write_system_dropin(dropin: &Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = dropin.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    replace_file(dropin, data, 0o644)
+}
+
+fn remove_system_dropin(dropin: &Path) -> Result<()> {
+    match fs::remove_file(dropin) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {}", dropin.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn systemctl(user: bool) -> Command {
+    if user {
+        let mut cmd = Command::new("systemctl");
+        cmd.arg("--user");
+        cmd
+    } else {
+        let mut cmd = Command::new("sudo");
+        cmd.arg("systemctl");
+        cmd
+    }
+}
+
+fn daemon_reload(user: bool) -> Result<()> {
+    let status = systemctl(user)
+        .arg("daemon-reload")
+        .status()
+        .context("Failed to run systemctl daemon-reload")?;
+    if !status.success() {
+        bail!("systemctl daemon-reload failed");
+    }
+    Ok(())
+}
+
+fn cmd_unit(name: &str, user: bool, config: &Config) -> Result<()> {
+    let unit = normalize_unit_name(name);
+    let dropin = dropin_path_for(&unit, user)?;
+
+    policy_for(&dropin, config)?.check(&dropin, "Edit")?;
+    if let Ok((entry, _)) = find_overlay_entry(&dropin) {
+        return edit_dropin(&dropin, &entry, HookEvent::Edit, config);
+    }
+
+    let storage = storage_dir_for(&dropin, false)?;
+    let overlay_dir = overlay_dir_for(&storage, &dropin);
+    storage_write(
+        &overlay_dir.join("path"),
+        dropin.as_os_str().as_bytes(),
+        false,
+    )?;
+    let stored_copy = overlay_dir.join("content");
+    storage_write(
+        &stored_copy,
+        format!("# Drop-in for {unit}, managed by nix-file-overlay\n[Service]\n").as_bytes(),
+        false,
+    )?;
+
+    let entry = OverlayEntry {
+        stored_copy,
+        original_target: None,
+        persistent: false,
+        created_at: Utc::now().to_rfc3339(),
+        mapping_key: Some(unit.clone()),
+        mapping_type: Some(DROPIN_MAPPING_TYPE.to_string()),
+        base_copy: None,
+        try_unit: None,
+        try_deadline: None,
+        expires_at: None,
+        until_logout: false,
+        boot_id: current_boot_id(),
+        mode: None,
+        uid: None,
+        gid: None,
+        base_path: None,
+        generation: None,
+    };
+    if let Err(e) = edit_dropin(&dropin, &entry, HookEvent::Overlay, config) {
+        remove_overlay_files(&entry);
+        return Err(e);
+    }
+
+    let reg_path = registry_path_for(&dropin, false)?;
+    let mut registry = load_registry(&reg_path)?;
+    registry.insert(path_key(&dropin), entry);
+    save_registry(&reg_path, &registry)?;
+
+    Ok(())
+}
+
+/// Edit the stored copy of a drop-in, install it and reload systemd.
+fn edit_dropin(
+    dropin: &Path,
+    entry: &OverlayEntry,
+    event: HookEvent,
+    config: &Config,
+) -> Result<()> {
+    let user = is_user_dropin(dropin);
+    let unit = entry.mapping_key.as_deref().unwrap_or_default();
+
+    if !edit_staged(dropin, &entry.stored_copy, event, config)? {
+        eprintln!("No changes.");
+        return Ok(());
+    }
+    install_dropin(&entry.stored_copy, dropin)?;
+    daemon_reload(user)?;
+    eprintln!("Installed drop-in {}", dropin.display());
+
+    if confirm(&format!("Restart {unit} now?"), false) {
+        let status = systemctl(user)
+            .args(["restart", unit])
+            .status()
+            .with_context(|| format!("Failed to restart {unit}"))?;
+        if !status.success() {
+            eprintln!("Warning: systemctl restart {unit} failed");
+        }
+    }
+    Ok(())
+}
+
+fn install_dropin(stored_copy: &Path, dropin: &Path) -> Result<()> {
+    if is_user_dropin(dropin) {
+        if let Some(parent) = dropin.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(stored_copy, dropin)
+            .with_context(|| format!("Failed to write {}", dropin.display()))?;
+        return Ok(());
+    }
+
+    let data = fs::read(stored_copy)
+        .with_context(|| format!("Failed to read {}", stored_copy.display()))?;
+    if is_root() {
+        return write_system_dropin(dropin, &data);
+    }
+    // The helper enforces the system policy on the drop-in path.
+    run_helper("install-dropin", &[], dropin, Some(&data))
+}
+
+fn remove_dropin(dropin: &Path) -> Result<()> {
+    let user = is_user_dropin(dropin);
+    if user {
+        fs::remove_file(dropin).ok();
+    } else if is_root() {
+        remove_system_dropin(dropin)?;
+    } else {
+        run_helper("remove-dropin", &[], dropin, None)?;
+    }
+    daemon_reload(user)
+}
+
+/// Translate a drop-in into the equivalent NixOS options and hand them to
+/// the apply backend.
+fn apply_dropin(dropin: &Path, entry: &OverlayEntry, repo: &Path, config: &Config) -> Result<()> {
+    let unit = entry.mapping_key.as_deref().unwrap_or_default();
+    let user = is_user_dropin(dropin);
+    let content = fs::read_to_string(&entry.stored_copy)
+        .with_context(|| format!("Failed to read {}", entry.stored_copy.display()))?;
+
+    let snippet = dropin_to_nix(unit, user, &content);
+    if snippet.is_empty() {
+        bail!("Drop-in for {unit} sets no options");
+    }
+
+    let (base, _) = unit.rsplit_once('.').unwrap_or((unit, "service"));
+    let mut files = Vec::new();
+    collect_repo_files(repo, &mut files);
+    let candidates: Vec<PathBuf> = files
+        .into_iter()
+        .filter(|f| f.extension().is_some_and(|e| e == "nix"))
+        .filter(|f| {
+            fs::read_to_string(f).is_ok_and(|text| {
+                text.contains(&format!("services.{base}"))
+                    || text.contains(&format!("services.\"{base}\""))
+            })
+        })
+        .collect();
+
+    eprintln!("Drop-in for {unit} as NixOS options:\n\n{snippet}");
+    if !candidates.is_empty() {
+        eprintln!("Modules that already configure {unit}:");
+        for c in &candidates {
+            eprintln!("  {}", c.display());
+        }
+    }
+
+    let prompt = format!(
+        "A systemd drop-in for {unit} was tested on this machine. The NixOS configuration \
+         repository is at {}. Make these settings permanent by adding the following options \
+         to the module that configures {unit} (or a suitable new one):\n\n{snippet}",
+        repo.display()
+    );
+    if !run_apply_backend(&prompt, repo, config)? {
+        eprintln!(
+            "\nAdd the options above to your configuration, then remove the drop-in with -r."
+        );
+    }
+    Ok(())
+}
+
+/// One key of a drop-in section with its values, in file order.
+struct DropinKey {
+    name: String,
+    values: Vec<String>,
+    /// The key was reset with an empty assignment, as in `ExecStart=`.
+    reset: bool,
+}
+
+/// Render drop-in sections as `systemd.services.<name>.serviceConfig`-style
+/// Nix assignments.
+fn dropin_to_nix(unit: &str, user: bool, content: &str) -> String {
+    let (base, kind) = unit.rsplit_once('.').unwrap_or((unit, "service"));
+    let prefix = if user { "systemd.user" } else { "systemd" };
+
+    let mut sections: Vec<(String, Vec<DropinKey>)> = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((name.to_string(), Vec::new()));
+            continue;
+        }
+        let (Some((_, keys)), Some((key, value))) = (sections.last_mut(), line.split_once('='))
+        else {
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim());
+        let idx = match keys.iter().position(|k| k.name == key) {
+            Some(idx) => idx,
+            None => {
+                keys.push(DropinKey {
+                    name: key.to_string(),
+                    values: Vec::new(),
+                    reset: false,
+                });
+                keys.len() - 1
+            }
+        };
+        if value.is_empty() {
+            keys[idx].values.clear();
+            keys[idx].reset = true;
+        } else {
+            keys[idx].values.push(value.to_string());
+        }
+    }
+
+    let mut out = String::new();
+    for (section, keys) in &sections {
+        let attr = format!("{}Config", section.to_lowercase());
+        if section == "Install" {
+            out.push_str("# [Install] has no effect in drop-ins; use wantedBy instead\n");
+            continue;
+        }
+        if attr != "unitConfig" && attr != format!("{kind}Config") {
+            out.push_str(&format!("# [{section}] does not apply to a {kind} unit\n"));
+            continue;
+        }
+        for key in keys {
+            let value = match key.values.as_slice() {
+                [] => "[ ]".to_string(),
+                [v] => nix_string(v),
+                vs => format!(
+                    "[ {} ]",
+                    vs.iter()
+                        .map(|v| nix_string(v))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                ),
+            };
+            // A reset replaces whatever the module sets.
+            let value = if key.reset {
+                format!("lib.mkForce {value}")
+            } else {
+                value
+            };
+            out.push_str(&format!(
+                "{prefix}.{kind}s.{}.{attr}.{} = {value};\n",
+                nix_attr(base),
+                key.name
+            ));
+        }
+    }
+    out
+}
+
+fn nix_string(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${");
+    format!("\"{escaped}\"")
+}
+
+fn nix_attr(name: &str) -> String {
+    let plain = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-'".contains(c));
+    if plain {
+        name.to_string()
+    } else {
+        nix_string(name)
+    }
+}
+
+// ── List command ─────────────────────────────────────────────────────
 
-    if is_bind_mounted(&abs_path)? {
-        bail!("Path is already overlaid: {}", abs_path.display());
+fn cmd_list(options: ListOptions, config: &Config) -> Result<()> {
+    let pruned = prune_dead_temp_entries();
+    for path in &pruned {
+        eprintln!("Pruned temporary overlay from a previous boot: {path}");
     }
 
-    let original_target = get_symlink_target(&abs_path);
-
-    // Read content through the symlink
-    let content = fs::read(&abs_path)
-        .with_context(|| format!("Failed to read file: {}", abs_path.display()))?;
+    let items = overlay_items(&options, config)?;
 
-    let storage = storage_dir_for(&abs_path, persistent)?;
-    let overlays_dir = storage.join("overlays");
-    fs::create_dir_all(&overlays_dir)?;
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&items)?);
+        return Ok(());
+    }
+    if options.ndjson {
+        for item in &items {
+            println!("{}", serde_json::to_string(item)?);
+        }
+        return Ok(());
+    }
 
-    let encoded = encode_path(&abs_path);
-    let stored_copy = overlays_dir.join(&encoded);
-    fs::write(&stored_copy, &content)?;
+    if items.is_empty() {
+        eprintln!("No active overlays.");
+        return Ok(());
+    }
 
-    // Make the copy writable
-    let mut perms = fs::metadata(&stored_copy)?.permissions();
-    perms.set_mode(perms.mode() | 0o600);
-    fs::set_permissions(&stored_copy, perms)?;
+    println!(
+        "{:<50} {:<8} {:<18} {:>8} {:>9} {:<16} CREATED",
+        "PATH", "STATUS", "PERSISTENCE", "SIZE", "DIFF", "EXPIRES"
+    );
+    println!("{}", "-".repeat(136));
 
-    let mapping_info = find_mapping_key_for_path(&abs_path)?;
+    for item in &items {
+        let persistence = if item["persistent"] == true {
+            format!("persistent/{}", item["scope"].as_str().unwrap_or("-"))
+        } else {
+            "temporary".to_string()
+        };
+        let diff = match &item["diff"] {
+            serde_json::Value::Null => "-".to_string(),
+            d => format!("+{}/-{}", d["added"], d["removed"]),
+        };
+        let expires = match item["expiresAt"].as_str() {
+            Some(at) => short_time(at),
+            None if item["untilLogout"] == true => "logout".to_string(),
+            None => "-".to_string(),
+        };
+        let mut notes = String::new();
+        if let Some(number) = item["generation"]["number"].as_u64() {
+            notes += &format!(" (generation {number})");
+        }
+        if item["baseDrifted"] == true {
+            notes += " (base changed)";
+        }
+        println!(
+            "{:<50} {:<8} {:<18} {:>8} {:>9} {:<16} {}{}",
+            item["path"].as_str().unwrap_or("?"),
+            item["status"].as_str().unwrap_or("?"),
+            persistence,
+            item["size"]
+                .as_u64()
+                .map_or("-".to_string(), |s| s.to_string()),
+            diff,
+            expires,
+            short_time(item["createdAt"].as_str().unwrap_or("")),
+            notes
+        );
+    }
 
-    // Bind mount the editable copy on top of the symlink path
-    run_sudo_mount(&stored_copy, &abs_path)?;
+    Ok(())
+}
 
-    let reg_path = registry_path_for(&abs_path, persistent)?;
-    let mut registry = load_registry(&reg_path)?;
-    registry.insert(
-        abs_path.to_string_lossy().into_owned(),
-        OverlayEntry {
-            stored_copy: stored_copy.clone(),
-            original_target,
-            persistent,
-            created_at: Utc::now().to_rfc3339(),
-            mapping_key: mapping_info.as_ref().map(|(k, _)| k.clone()),
-            mapping_type: mapping_info.map(|(_, t)| t),
-        },
-    );
-    save_registry(&reg_path, &registry)?;
+/// One JSON object per overlay matching the `--list` filters, sorted by path.
+fn overlay_items(options: &ListOptions, config: &Config) -> Result<Vec<serde_json::Value>> {
+    let mounts = MountTable::read()?;
+    let managed = collect_managed_files(config).unwrap_or_default();
+    let cutoff = options
+        .older_than
+        .map(|d| {
+            Utc::now()
+                .checked_sub_signed(d)
+                .context("--older-than is too long")
+        })
+        .transpose()?;
+
+    let mut items = Vec::new();
+    for (path, entry, scope) in load_all_overlays() {
+        if options.scope.is_some_and(|s| s.as_str() != scope) {
+            continue;
+        }
+        if let Some(cutoff) = cutoff
+            && chrono::DateTime::parse_from_rfc3339(&entry.created_at)
+                .is_ok_and(|created| created > cutoff)
+        {
+            continue;
+        }
 
-    eprintln!(
-        "Overlaid {} ({})",
-        abs_path.display(),
-        if persistent {
-            "persistent"
+        let abs_path = key_path(&path);
+        let state = if is_dropin_entry(&entry) {
+            if abs_path.exists() {
+                MountState::Ours
+            } else {
+                MountState::Unmounted
+            }
         } else {
-            "temporary"
-        },
-    );
+            mounts.overlay_state(&abs_path, &entry.stored_copy)
+        };
+        let status = match (state, &entry.try_deadline) {
+            (MountState::Ours, Some(_)) => "trying",
+            (MountState::Ours, None) => "active",
+            (MountState::Stacked { .. }, _) => "stacked",
+            (MountState::Foreign, _) => "foreign",
+            (MountState::Unmounted, _) => "stale",
+        };
+        if options.stale && status != "stale" {
+            continue;
+        }
 
-    if !no_edit {
-        let editor = std::env::var("NIX_FILE_OVERLAY_EDITOR")
-            .or_else(|_| std::env::var("EDITOR"))
-            .unwrap_or_else(|_| "vi".to_string());
-        let status = Command::new(&editor)
-            .arg(&abs_path)
-            .status()
-            .with_context(|| format!("Failed to launch editor: {editor}"))?;
-        if !status.success() {
-            eprintln!("Editor exited with non-zero status");
+        let repo_relative = managed
+            .iter()
+            .find(|f| f.path == abs_path)
+            .and_then(|f| f.repo_relative.clone());
+        let meta = fs::metadata(&entry.stored_copy).ok();
+        let content = fs::read(&entry.stored_copy).ok();
+        let diff = match (&content, read_original_content(&entry)) {
+            (Some(new), Some(old)) => Some(diff_stat(&old, new)),
+            _ => None,
+        };
+        let expires = match (&entry.expires_at, &entry.try_deadline) {
+            (_, Some(deadline)) => Some(deadline.clone()),
+            (Some(at), None) => Some(at.clone()),
+            (None, None) => None,
+        };
+
+        // Paths need not be UTF-8; `pathKey` keeps them intact (see `path_key`).
+        items.push(serde_json::json!({
+            "path": abs_path.to_string_lossy(),
+            "pathKey": path,
+            "status": status,
+            "scope": scope,
+            "persistent": entry.persistent,
+            "createdAt": entry.created_at,
+            "mappingKey": entry.mapping_key,
+            "mappingType": entry.mapping_type,
+            "repoRelative": repo_relative,
+            "storedCopy": entry.stored_copy.to_string_lossy(),
+            "baseDrifted": base_drifted(&abs_path, &entry),
+            "diff": diff.map(|(added, removed)| serde_json::json!({
+                "added": added,
+                "removed": removed,
+            })),
+            "size": meta.as_ref().map(|m| m.len()),
+            "owner": meta.as_ref().map(|m| format!("{}:{}", user_name(m.uid()), group_name(m.gid()))),
+            "mode": meta.as_ref().map(|m| format!("{:04o}", m.mode() & 0o7777)),
+            "expiresAt": expires,
+            "untilLogout": entry.until_logout,
+            "tryDeadline": entry.try_deadline,
+            "generation": entry.generation.as_ref().map(|g| serde_json::json!({
+                "profile": g.profile,
+                "number": g.number,
+                "source": g.source.to_string_lossy(),
+            })),
+        }));
+    }
+    items.sort_by(|a, b| a["path"].as_str().cmp(&b["path"].as_str()));
+    Ok(items)
+}
+
+// ── Status command ───────────────────────────────────────────────────
+
+/// Longest wait between checks, so newly created registry directories get
+/// watched eventually.
+const STATUS_RESCAN_SECS: i64 = 60;
+
+/// Print a `snapshot` event and, with `follow`, one event per change:
+/// `registry` (an overlay was added, edited or removed), `mount` (a mount
+/// appeared or went away), `generation` (a profile switched) and `expired`
+/// (an overlay's TTL ran out). Every event carries the full overlay list.
+fn cmd_status(follow: bool, config: &Config) -> Result<()> {
+    let mut items = overlay_items(&ListOptions::default(), config)?;
+    emit_status("snapshot", &items, None)?;
+    if !follow {
+        return Ok(());
+    }
+
+    let mut watcher = StatusWatcher::new()?;
+    let mut reported_expired: Vec<String> = Vec::new();
+    loop {
+        let timeout = next_expiry_in(&items)
+            .map_or(STATUS_RESCAN_SECS, |secs| secs.clamp(0, STATUS_RESCAN_SECS));
+        let woke = watcher.wait(timeout)?;
+
+        let previous =
+            std::mem::replace(&mut items, overlay_items(&ListOptions::default(), config)?);
+        if woke.generation {
+            emit_status("generation", &items, None)?;
+        } else if items != previous {
+            let kind = if woke.registry { "registry" } else { "mount" };
+            emit_status(kind, &items, None)?;
+        }
+
+        for item in &items {
+            let path = item["path"].as_str().unwrap_or_default().to_string();
+            let expired = item["expiresAt"]
+                .as_str()
+                .and_then(|at| chrono::DateTime::parse_from_rfc3339(at).ok())
+                .is_some_and(|at| at <= Utc::now());
+            if expired && !reported_expired.contains(&path) {
+                emit_status("expired", &items, Some(&path))?;
+                reported_expired.push(path);
+            }
         }
     }
+}
 
+fn emit_status(kind: &str, items: &[serde_json::Value], path: Option<&str>) -> Result<()> {
+    let count = |status: &str| items.iter().filter(|i| i["status"] == status).count();
+    let event = serde_json::json!({
+        "event": kind,
+        "time": Utc::now().to_rfc3339(),
+        "path": path,
+        "active": count("active") + count("trying"),
+        "stale": count("stale"),
+        "drifted": items.iter().filter(|i| i["baseDrifted"] == true).count(),
+        "overlays": items,
+    });
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{event}")?;
+    // Widgets read line by line from a pipe.
+    stdout.flush()?;
     Ok(())
 }
 
-// ── List command ─────────────────────────────────────────────────────
+/// Seconds until the next overlay expires, if any does.
+fn next_expiry_in(items: &[serde_json::Value]) -> Option<i64> {
+    items
+        .iter()
+        .filter_map(|i| chrono::DateTime::parse_from_rfc3339(i["expiresAt"].as_str()?).ok())
+        .map(|at| (at.with_timezone(&Utc) - Utc::now()).num_seconds() + 1)
+        .filter(|secs| *secs > 0)
+        .min()
+}
 
-fn cmd_list() -> Result<()> {
-    let mut entries: Vec<(String, OverlayEntry, String)> = Vec::new();
+/// What woke up a `StatusWatcher`.
+#[derive(Default)]
+struct StatusWake {
+    registry: bool,
+    generation: bool,
+}
 
-    if let Ok(data_dir) = get_data_dir() {
-        let reg_path = data_dir.join("registry.json");
-        if let Ok(reg) = load_registry(&reg_path) {
-            for (path, entry) in reg {
-                entries.push((path, entry, "user".to_string()));
+/// inotify on the registry and profile directories, plus the mount table,
+/// which signals changes through `poll`.
+struct StatusWatcher {
+    inotify: fs::File,
+    mountinfo: fs::File,
+    registry_dirs: Vec<PathBuf>,
+    profile_dirs: Vec<PathBuf>,
+    watches: HashMap<i32, bool>,
+}
+
+impl StatusWatcher {
+    fn new() -> Result<Self> {
+        use std::os::fd::FromRawFd;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to set up inotify");
+        }
+        let inotify = unsafe { fs::File::from_raw_fd(fd) };
+        let mountinfo = fs::File::open("/proc/self/mountinfo")
+            .context("Failed to open /proc/self/mountinfo")?;
+
+        let registry_dirs = known_registry_paths()
+            .iter()
+            .filter_map(|p| Some(p.parent()?.to_path_buf()))
+            .collect();
+        let user = std::env::var("USER").unwrap_or_default();
+        let mut profile_dirs = vec![
+            PathBuf::from("/nix/var/nix/profiles"),
+            PathBuf::from(format!("/nix/var/nix/profiles/per-user/{user}")),
+        ];
+        if let Ok(home) = get_home_dir() {
+            profile_dirs.push(home.join(".local/state/nix/profiles"));
+        }
+
+        let mut watcher = Self {
+            inotify,
+            mountinfo,
+            registry_dirs,
+            profile_dirs,
+            watches: HashMap::new(),
+        };
+        watcher.add_watches();
+        Ok(watcher)
+    }
+
+    /// Watch every directory that exists by now. Adding a watch twice is a
+    /// no-op, so this is repeated on each wake-up.
+    fn add_watches(&mut self) {
+        use std::os::fd::AsRawFd;
+
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE | libc::IN_DELETE;
+        let dirs = self
+            .registry_dirs
+            .iter()
+            .map(|d| (d, false))
+            .chain(self.profile_dirs.iter().map(|d| (d, true)));
+        for (dir, is_profile) in dirs {
+            let Ok(c_path) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+                continue;
+            };
+            let wd =
+                unsafe { libc::inotify_add_watch(self.inotify.as_raw_fd(), c_path.as_ptr(), mask) };
+            if wd >= 0 {
+                self.watches.insert(wd, is_profile);
             }
         }
     }
 
-    let sys_reg_path = get_system_data_dir().join("registry.json");
-    if let Ok(reg) = load_registry(&sys_reg_path) {
-        for (path, entry) in reg {
-            entries.push((path, entry, "system".to_string()));
+    /// Block until something changed or `timeout_secs` passed.
+    fn wait(&mut self, timeout_secs: i64) -> Result<StatusWake> {
+        use std::io::{Seek, SeekFrom};
+        use std::os::fd::AsRawFd;
+
+        let mut fds = [
+            libc::PollFd {
+                fd: self.inotify.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::PollFd {
+                fd: self.mountinfo.as_raw_fd(),
+                events: libc::POLLPRI,
+                revents: 0,
+            },
+        ];
+        let timeout_ms = (timeout_secs * 1000).clamp(0, i32::MAX as i64) as i32;
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, timeout_ms) };
+        if ready < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(err).context("poll failed");
+            }
         }
+
+        let mut wake = StatusWake::default();
+        if fds[0].revents & libc::POLLIN != 0 {
+            self.read_events(&mut wake);
+        }
+        if fds[1].revents & (libc::POLLPRI | libc::POLLERR) != 0 {
+            // Reading the table acknowledges the change.
+            self.mountinfo.seek(SeekFrom::Start(0))?;
+            std::io::copy(&mut self.mountinfo, &mut std::io::sink())?;
+        }
+        self.add_watches();
+        Ok(wake)
     }
 
-    if let Ok(tmp_dir) = get_tmp_dir() {
-        let reg_path = tmp_dir.join("registry.json");
-        if let Ok(reg) = load_registry(&reg_path) {
-            for (path, entry) in reg {
-                if !entries.iter().any(|(p, _, _)| p == &path) {
-                    entries.push((path, entry, "temp".to_string()));
+    fn read_events(&mut self, wake: &mut StatusWake) {
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = self.inotify.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            // struct inotify_event { int wd; u32 mask, cookie, len; char name[len]; }
+            let mut offset = 0;
+            while offset + 16 <= n {
+                let field = |at: usize| {
+                    u32::from_ne_bytes(buf[offset + at..offset + at + 4].try_into().unwrap())
+                };
+                let wd = field(0) as i32;
+                let len = field(12) as usize;
+                let name_end = (offset + 16 + len).min(n);
+                let name = buf[offset + 16..name_end]
+                    .split(|b| *b == 0)
+                    .next()
+                    .unwrap_or_default();
+                match self.watches.get(&wd) {
+                    Some(true) => wake.generation = true,
+                    Some(false) if name == b"registry.json" => wake.registry = true,
+                    _ => {}
                 }
+                offset += 16 + len;
             }
         }
     }
+}
 
-    if entries.is_empty() {
-        eprintln!("No active overlays.");
-        return Ok(());
+/// Whether the Nix-managed file behind `abs_path` changed since it was
+/// overlaid, e.g. by a rebuild. Unknown for entries without a base path.
+fn base_drifted(abs_path: &Path, entry: &OverlayEntry) -> Option<bool> {
+    let base_path = entry.base_path.as_ref()?;
+    let current = fs::canonicalize(abs_path).ok()?;
+    if current == *base_path {
+        return Some(false);
     }
+    // A new store path can still hold the same content.
+    let base = read_original_content(entry)?;
+    Some(fs::read(&current).ok()? != base)
+}
 
-    println!(
-        "{:<60} {:<10} {:<12} {}",
-        "PATH", "STATUS", "PERSISTENCE", "CREATED"
-    );
-    println!("{}", "-".repeat(100));
+/// Lines added and removed between `old` and `new`.
+fn diff_stat(old: &[u8], new: &[u8]) -> (usize, usize) {
+    let old = String::from_utf8_lossy(old);
+    let new = String::from_utf8_lossy(new);
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+
+    // Longest common subsequence; very large files fall back to counting
+    // lines missing on either side.
+    let common = if a.len().saturating_mul(b.len()) <= 4_000_000 {
+        let mut row = vec![0usize; b.len() + 1];
+        for line in &a {
+            let mut diag = 0;
+            for (j, other) in b.iter().enumerate() {
+                let above = row[j + 1];
+                row[j + 1] = if line == other {
+                    diag + 1
+                } else {
+                    row[j + 1].max(row[j])
+                };
+                diag = above;
+            }
+        }
+        row[b.len()]
+    } else {
+        a.iter().filter(|l| b.contains(l)).count()
+    };
+    (b.len() - common.min(b.len()), a.len() - common.min(a.len()))
+}
 
-    for (path, entry, scope) in &entries {
-        let mounted = is_bind_mounted(Path::new(path)).unwrap_or(false);
-        let status = if mounted { "active" } else { "stale" };
-        let persistence = if entry.persistent {
-            format!("persistent/{scope}")
-        } else {
-            "temporary".to_string()
-        };
-        println!(
-            "{:<60} {:<10} {:<12} {}",
-            path, status, persistence, entry.created_at
-        );
-    }
+fn short_time(rfc3339: &str) -> String {
+    chrono::DateTime::parse_from_rfc3339(rfc3339)
+        .map(|t| {
+            t.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_else(|_| rfc3339.to_string())
+}
 
-    Ok(())
+fn user_name(uid: u32) -> String {
+    lookup_name("/etc/passwd", uid).unwrap_or_else(|| uid.to_string())
+}
+
+fn group_name(gid: u32) -> String {
+    lookup_name("/etc/group", gid).unwrap_or_else(|| gid.to_string())
+}
+
+/// Reverse of `lookup_id`.
+fn lookup_name(db: &str, id: u32) -> Option<String> {
+    fs::read_to_string(db).ok()?.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        (fields.get(2)?.parse::<u32>().ok()? == id).then(|| fields[0].to_string())
+    })
 }
 
 // ── Remove command ───────────────────────────────────────────────────
 
-fn cmd_remove(path: &Path) -> Result<()> {
+fn cmd_remove(path: &Path, registry_path: Option<&Path>, config: &Config) -> Result<()> {
     let abs_path = resolve_path(path).unwrap_or_else(|_| {
         if path.is_absolute() {
             path.to_path_buf()
@@ -488,9 +4367,12 @@ fn cmd_remove(path: &Path) -> Result<()> {
             std::env::current_dir().unwrap_or_default().join(path)
         }
     });
-    let path_str = abs_path.to_string_lossy().into_owned();
+    let path_str = path_key(&abs_path);
 
-    let registries = collect_registry_paths(&abs_path)?;
+    let registries = match registry_path {
+        Some(p) => vec![p.to_path_buf()],
+        None => collect_registry_paths(&abs_path)?,
+    };
 
     let mut found = false;
     for reg_path in &registries {
@@ -501,15 +4383,47 @@ fn cmd_remove(path: &Path) -> Result<()> {
         if let Some(entry) = registry.remove(&path_str) {
             found = true;
 
-            // Unmount to reveal the original symlink underneath
-            if is_bind_mounted(&abs_path).unwrap_or(false) {
-                run_sudo_umount(&abs_path)?;
-            }
+            // The original comes back once the overlay is gone.
+            let candidate = entry
+                .base_copy
+                .clone()
+                .filter(|b| b.exists())
+                .unwrap_or_else(|| entry.stored_copy.clone());
+            check_change(&abs_path, &candidate, HookEvent::Remove, config)?;
 
-            if entry.stored_copy.exists() {
-                fs::remove_file(&entry.stored_copy).ok();
+            if is_dropin_entry(&entry) {
+                remove_dropin(&abs_path)?;
+            } else {
+                match MountTable::read()?.overlay_state(&abs_path, &entry.stored_copy) {
+                    // Unmount to reveal the original symlink underneath
+                    MountState::Ours => run_sudo_umount(&abs_path)?,
+                    MountState::Stacked {
+                        layers,
+                        ours_on_top: true,
+                    } => {
+                        run_sudo_umount(&abs_path)?;
+                        eprintln!(
+                            "Warning: {} more mount(s) remain stacked on {}",
+                            layers - 1,
+                            abs_path.display()
+                        );
+                    }
+                    MountState::Stacked {
+                        ours_on_top: false, ..
+                    } => bail!(
+                        "Another mount is stacked on top of the overlay at {}; unmount it first",
+                        abs_path.display()
+                    ),
+                    MountState::Foreign => eprintln!(
+                        "Warning: leaving a foreign mount at {} in place",
+                        abs_path.display()
+                    ),
+                    MountState::Unmounted => {}
+                }
             }
 
+            remove_overlay_files(&entry);
+
             save_registry(reg_path, &registry)?;
             eprintln!("Removed overlay for {}", abs_path.display());
         }
@@ -522,69 +4436,431 @@ fn cmd_remove(path: &Path) -> Result<()> {
     Ok(())
 }
 
-// ── Apply command ────────────────────────────────────────────────────
+// ── Store path resolution ────────────────────────────────────────────
 
-fn cmd_apply(path: &Path) -> Result<()> {
-    let abs_path = resolve_path(path)?;
-    let path_str = abs_path.to_string_lossy().into_owned();
+const OVERLAY_META_PATH: &str = "/etc/nix-file-overlay/meta.json";
 
-    let registries = collect_registry_paths(&abs_path)?;
-    let mut found_entry: Option<(OverlayEntry, PathBuf)> = None;
+/// Metadata recorded by the NixOS module at activation time.
+#[derive(Deserialize, Default)]
+struct OverlayMeta {
+    /// Store path of the flake source the running generation was built from.
+    #[serde(rename = "selfSource")]
+    self_source: Option<String>,
+}
 
-    for reg_path in &registries {
-        let registry = match load_registry(reg_path) {
-            Ok(r) => r,
-            Err(_) => continue,
+fn load_overlay_meta() -> OverlayMeta {
+    fs::read_to_string(OVERLAY_META_PATH)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+/// Where a `/nix/store` path comes from.
+enum StoreOrigin {
+    /// Inside the source tree of this repo; holds the repo-relative path.
+    SelfRepo(String),
+    /// Inside the source tree of a `flake.lock` input.
+    Input { name: String, rel: String },
+    /// Some other store path (generated files, packages, ...).
+    Unknown,
+}
+
+/// Split `/nix/store/<hash>-<name>/rest` into the store root and `rest`.
+fn split_store_path(path: &str) -> Option<(&str, &str)> {
+    let after = path.strip_prefix("/nix/store/")?;
+    let root_len = "/nix/store/".len() + after.find('/').unwrap_or(after.len());
+    let rest = path[root_len..].trim_start_matches('/');
+    Some((&path[..root_len], rest))
+}
+
+/// Resolve a store path against the self source recorded by the module and
+/// the inputs locked in `flake.lock`.
+fn resolve_store_origin(store_path: &str, repo: &Path) -> StoreOrigin {
+    let Some((root, rest)) = split_store_path(store_path) else {
+        return StoreOrigin::Unknown;
+    };
+    if rest.is_empty() {
+        return StoreOrigin::Unknown;
+    }
+
+    let meta = load_overlay_meta();
+    if meta.self_source.as_deref() == Some(root) {
+        return StoreOrigin::SelfRepo(rest.to_string());
+    }
+
+    // Prefer the lock file the running generation was built from.
+    let lock_path = meta
+        .self_source
+        .as_deref()
+        .map(|s| Path::new(s).join("flake.lock"))
+        .filter(|p| p.exists())
+        .unwrap_or_else(|| repo.join("flake.lock"));
+    if let Some(name) = find_flake_input_for_root(&lock_path, root) {
+        return StoreOrigin::Input {
+            name,
+            rel: rest.to_string(),
         };
-        if let Some(entry) = registry.get(&path_str) {
-            found_entry = Some((entry.clone(), reg_path.clone()));
-            break;
+    }
+
+    StoreOrigin::Unknown
+}
+
+/// Find the resolved origin of the first store path in `abs_path`'s live
+/// symlink chain that belongs to a known source tree.
+fn store_origin_of_chain(abs_path: &Path, repo: &Path) -> StoreOrigin {
+    for hop in symlink_chain(abs_path) {
+        let origin = resolve_store_origin(&hop.to_string_lossy(), repo);
+        if !matches!(origin, StoreOrigin::Unknown) {
+            return origin;
+        }
+    }
+    StoreOrigin::Unknown
+}
+
+fn find_flake_input_for_root(lock_path: &Path, root: &str) -> Option<String> {
+    let data = fs::read_to_string(lock_path).ok()?;
+    let lock: serde_json::Value = serde_json::from_str(&data).ok()?;
+    let nodes = lock.get("nodes")?.as_object()?;
+
+    for (name, node) in nodes {
+        let Some(nar_hash) = node.pointer("/locked/narHash").and_then(|h| h.as_str()) else {
+            continue;
+        };
+        if fixed_source_store_path(nar_hash, "source").as_deref() == Some(root) {
+            return Some(name.clone());
+        }
+    }
+    None
+}
+
+/// Compute the store path Nix uses for a fetched tree with the given SRI
+/// `narHash` (recursive sha256 fixed-output path).
+fn fixed_source_store_path(nar_hash: &str, name: &str) -> Option<String> {
+    let digest = STANDARD.decode(nar_hash.strip_prefix("sha256-")?).ok()?;
+    let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+
+    let fingerprint = format!("source:sha256:{hex}:/nix/store:{name}");
+    let hash = Sha256::digest(fingerprint.as_bytes());
+
+    let mut compressed = [0u8; 20];
+    for (i, b) in hash.iter().enumerate() {
+        compressed[i % 20] ^= b;
+    }
+    Some(format!("/nix/store/{}-{name}", nix_base32(&compressed)))
+}
+
+fn nix_base32(bytes: &[u8]) -> String {
+    const CHARS: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+    let len = (bytes.len() * 8 - 1) / 5 + 1;
+    (0..len)
+        .rev()
+        .map(|n| {
+            let b = n * 5;
+            let (i, j) = (b / 8, b % 8);
+            let lo = bytes[i] >> j;
+            let hi = if i + 1 < bytes.len() {
+                bytes[i + 1].checked_shl(8 - j as u32).unwrap_or(0)
+            } else {
+                0
+            };
+            CHARS[((lo | hi) & 0x1f) as usize] as char
+        })
+        .collect()
+}
+
+fn print_upstream_guidance(abs_path: &Path, name: &str, rel: &str) {
+    eprintln!(
+        "{} comes from the flake input '{name}' ({rel}), not from this repo.",
+        abs_path.display()
+    );
+    eprintln!(
+        "  Override the option that installs it in your configuration, or patch the \
+         input (e.g. a fork or a patched `inputs.{name}`) to make the change permanent."
+    );
+    eprintln!("  The overlay was left in place.");
+}
+
+// ── Source locator ───────────────────────────────────────────────────
+
+/// Directories never searched when locating sources by content.
+const LOCATOR_SKIP_DIRS: &[&str] = &["target", "result", "node_modules"];
+
+/// Files larger than this are not considered as sources.
+const LOCATOR_MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Minimum run of consecutive distinctive lines to count as an embedded copy,
+/// and minimum number of distinctive lines for a verbatim copy to be applied
+/// without asking.
+const LOCATOR_MIN_RUN: usize = 3;
+
+enum CandidateKind {
+    /// The file content equals the original.
+    Verbatim,
+    /// A Nix file with a run of the original's lines, starting at `line`.
+    NixString { line: usize, matched: usize },
+}
+
+struct SourceCandidate {
+    path: PathBuf,
+    kind: CandidateKind,
+    score: usize,
+}
+
+/// Search `repo` for files that are, or embed, `original`. Candidates are
+/// ranked best first.
+fn locate_sources(repo: &Path, original: &[u8]) -> Vec<SourceCandidate> {
+    let original_text = std::str::from_utf8(original).ok();
+    let original_lines: Vec<&str> = original_text
+        .map(|t| t.lines().map(str::trim).collect())
+        .unwrap_or_default();
+    // Every empty file in the repo would be a verbatim copy of an empty one.
+    if original.trim_ascii().is_empty() {
+        return Vec::new();
+    }
+
+    let mut candidates = Vec::new();
+    let mut files = Vec::new();
+    collect_repo_files(repo, &mut files);
+
+    for path in files {
+        let Ok(content) = fs::read(&path) else {
+            continue;
+        };
+        if content.trim_ascii_end() == original.trim_ascii_end() {
+            candidates.push(SourceCandidate {
+                path,
+                kind: CandidateKind::Verbatim,
+                score: usize::MAX,
+            });
+            continue;
+        }
+
+        if original_lines.is_empty() || path.extension().is_none_or(|e| e != "nix") {
+            continue;
+        }
+        let Ok(text) = std::str::from_utf8(&content) else {
+            continue;
+        };
+        let nix_lines: Vec<&str> = text.lines().map(str::trim).collect();
+        if let Some((line, matched)) = longest_embedded_run(&original_lines, &nix_lines)
+            && matched >= LOCATOR_MIN_RUN
+        {
+            candidates.push(SourceCandidate {
+                path,
+                kind: CandidateKind::NixString { line, matched },
+                score: matched,
+            });
+        }
+    }
+
+    candidates.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.path.cmp(&b.path)));
+    candidates
+}
+
+fn collect_repo_files(dir: &Path, out: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            if !name.starts_with('.') && !LOCATOR_SKIP_DIRS.contains(&name.as_ref()) {
+                collect_repo_files(&entry.path(), out);
+            }
+        } else if file_type.is_file()
+            && entry
+                .metadata()
+                .is_ok_and(|m| m.len() <= LOCATOR_MAX_FILE_SIZE)
+        {
+            out.push(entry.path());
+        }
+    }
+}
+
+/// Number of distinctive lines in `content`.
+fn distinctive_line_count(content: &[u8]) -> usize {
+    std::str::from_utf8(content).map_or(0, |text| {
+        text.lines()
+            .filter(|l| is_distinctive_line(l.trim()))
+            .count()
+    })
+}
+
+/// Lines that are likely unique to this file, as opposed to braces, blank
+/// lines and other boilerplate.
+fn is_distinctive_line(line: &str) -> bool {
+    line.len() >= 8 && line.chars().filter(|c| c.is_alphanumeric()).count() >= 4
+}
+
+/// Find the longest run of consecutive lines shared by `original` and `nix`,
+/// counting only distinctive lines. Returns the 1-based line in `nix` where
+/// the run starts and the number of distinctive lines in it.
+fn longest_embedded_run(original: &[&str], nix: &[&str]) -> Option<(usize, usize)> {
+    let mut best: Option<(usize, usize)> = None;
+    // prev[j] = distinctive lines in the run ending at original[i-1], nix[j-1]
+    let mut prev = vec![0usize; nix.len() + 1];
+    let mut prev_len = vec![0usize; nix.len() + 1];
+
+    for orig_line in original {
+        let mut cur = vec![0usize; nix.len() + 1];
+        let mut cur_len = vec![0usize; nix.len() + 1];
+        for (j, nix_line) in nix.iter().enumerate() {
+            if orig_line == nix_line {
+                let weight = usize::from(is_distinctive_line(orig_line));
+                cur[j + 1] = prev[j] + weight;
+                cur_len[j + 1] = prev_len[j] + 1;
+                if cur[j + 1] > best.map_or(0, |(_, m)| m) {
+                    best = Some((j + 2 - cur_len[j + 1], cur[j + 1]));
+                }
+            }
+        }
+        prev = cur;
+        prev_len = cur_len;
+    }
+
+    best
+}
+
+/// Apply using content-located candidates: write through when exactly one
+/// repo file is a verbatim copy, otherwise report the ranked candidates. A
+/// verbatim copy of a short or boilerplate original may be a coincidence, so
+/// that one is only written after asking.
+fn apply_located(
+    abs_path: &Path,
+    modified_content: &[u8],
+    original: &[u8],
+    candidates: &[SourceCandidate],
+    config: &Config,
+) -> Result<()> {
+    let verbatim: Vec<&SourceCandidate> = candidates
+        .iter()
+        .filter(|c| matches!(c.kind, CandidateKind::Verbatim))
+        .collect();
+
+    if let [only] = verbatim.as_slice()
+        && (distinctive_line_count(original) >= LOCATOR_MIN_RUN
+            || confirm(
+                &format!(
+                    "{} has the same content as {}, but it is too short to be sure. Write the changes there?",
+                    only.path.display(),
+                    abs_path.display()
+                ),
+                false,
+            ))
+    {
+        fs::write(&only.path, modified_content)
+            .with_context(|| format!("Failed to write to {}", only.path.display()))?;
+        cmd_remove(abs_path, None, config)?;
+        eprintln!(
+            "Applied to {} (matched by content). Run nixos-rebuild to make permanent.",
+            only.path.display()
+        );
+        return Ok(());
+    }
+
+    eprintln!(
+        "Found possible sources for {} by content:\n",
+        abs_path.display()
+    );
+    for c in candidates.iter().take(10) {
+        match c.kind {
+            CandidateKind::Verbatim => eprintln!("  {}  (verbatim copy)", c.path.display()),
+            CandidateKind::NixString { line, matched } => eprintln!(
+                "  {}:{line}  (embedded, {matched} matching lines)",
+                c.path.display()
+            ),
         }
     }
+    eprintln!("\nChanges:");
+    eprintln!(
+        "{}",
+        generate_diff(&Some(original.to_vec()), modified_content)
+    );
+    eprintln!(
+        "\nEdit the matching source above to apply these changes permanently. \
+         The overlay was left in place; remove it with -r once applied."
+    );
 
-    let (entry, _reg_path) = found_entry.with_context(|| {
-        format!(
-            "No overlay found for {}. Overlay the file first.",
-            abs_path.display()
-        )
-    })?;
+    Ok(())
+}
+
+// ── Apply command ────────────────────────────────────────────────────
+
+fn cmd_apply(path: &Path, config: &Config) -> Result<()> {
+    let abs_path = resolve_path(path)?;
+    let (entry, _reg_path) = find_overlay_entry(&abs_path)?;
+
+    if is_dropin_entry(&entry) {
+        return apply_dropin(
+            &abs_path,
+            &entry,
+            &repo_for_path(&abs_path, config)?,
+            config,
+        );
+    }
 
     // Read the current (overlaid/modified) content
     let modified_content = fs::read(&abs_path)
         .with_context(|| format!("Failed to read overlaid file: {}", abs_path.display()))?;
 
-    // Read the original content from the store path
-    let original_content = if let Some(ref orig) = entry.original_target {
-        fs::read(orig).ok()
-    } else {
-        None
-    };
+    let original_content = read_original_content(&entry);
+    // The diffs below are against the current original, so a rolled-back
+    // file shows up as reverting everything since that generation.
+    if let Some(g) = &entry.generation {
+        eprintln!(
+            "Note: {} holds its content from {} generation {}; applying makes that the source.",
+            abs_path.display(),
+            g.profile,
+            g.number
+        );
+    }
 
     let home = get_home_dir()?;
-    let user_repo = get_user_repo()?;
-    let system_repo = get_system_repo(&user_repo);
+    let repo = &repo_for_path(&abs_path, config)?;
 
-    let repo = if abs_path.starts_with(&home) {
-        &user_repo
-    } else {
-        &system_repo
-    };
+    // A stale mapping would make us write to the wrong repo file, so never
+    // apply automatically from one.
+    if let (Some(_), Some(_)) = lookup_mapping_checked(&abs_path)? {
+        eprintln!("\nMapping scan for {}:\n", abs_path.display());
+        print_mapping_scan(&abs_path, config)?;
+        bail!(
+            "Refusing to apply automatically from a stale mapping. \
+             Finish the rebuild/activation and retry, or apply manually using the scan above."
+        );
+    }
+
+    // Files owned by another flake input are also `-source` trees; never
+    // write those into our repo.
+    if let StoreOrigin::Input { name, rel } = store_origin_of_chain(&abs_path, repo) {
+        print_upstream_guidance(&abs_path, &name, &rel);
+        return Ok(());
+    }
 
     if abs_path.starts_with(&home) {
         if let Some(repo_file) = try_apply_hm(&abs_path, &modified_content, repo)? {
-            cmd_remove(&abs_path)?;
+            cmd_remove(&abs_path, None, config)?;
             eprintln!(
                 "Applied to {}. Run nixos-rebuild to make permanent.",
                 repo_file.display()
             );
             return Ok(());
         }
-    } else if abs_path.starts_with("/etc/") {
-        if let Some(result) = try_apply_etc(&abs_path, &modified_content, &original_content, repo)?
-        {
-            cmd_remove(&abs_path)?;
-            eprintln!("{result}");
-            return Ok(());
+    } else if abs_path.starts_with("/etc/")
+        && let Some(result) = try_apply_etc(&abs_path, &modified_content, &original_content, repo)?
+    {
+        cmd_remove(&abs_path, None, config)?;
+        eprintln!("{result}");
+        return Ok(());
+    }
+
+    if let Some(original) = &original_content {
+        let candidates = locate_sources(repo, original);
+        if !candidates.is_empty() {
+            return apply_located(&abs_path, &modified_content, original, &candidates, config);
         }
     }
 
@@ -594,23 +4870,32 @@ fn cmd_apply(path: &Path) -> Result<()> {
         &original_content,
         &entry,
         repo,
+        config,
     )?;
 
     Ok(())
 }
 
-fn try_apply_hm(abs_path: &Path, modified_content: &[u8], repo: &Path) -> Result<Option<PathBuf>> {
-    let hm_mapping_path = get_hm_mapping_path()?;
-    if !hm_mapping_path.exists() {
-        return Ok(None);
+/// Read the Nix-managed content an overlay was created from.
+fn read_original_content(entry: &OverlayEntry) -> Option<Vec<u8>> {
+    if let Some(content) = entry.base_copy.as_ref().and_then(|b| fs::read(b).ok()) {
+        return Some(content);
     }
+    // Older entries have no base copy; this only works while unmounted.
+    entry
+        .original_target
+        .as_ref()
+        .and_then(|o| fs::read(o).ok())
+}
 
-    let data = fs::read_to_string(&hm_mapping_path)?;
-    let mapping: HashMap<String, HmMappingEntry> = serde_json::from_str(&data)?;
+fn try_apply_hm(abs_path: &Path, modified_content: &[u8], repo: &Path) -> Result<Option<PathBuf>> {
+    let Some(mapping) = load_hm_mapping()? else {
+        return Ok(None);
+    };
     let home = get_home_dir()?;
     let rel = abs_path.strip_prefix(&home)?;
 
-    for (_key, entry) in &mapping {
+    for entry in mapping.values() {
         if entry.entry_type.as_deref() != Some("repo-source") {
             continue;
         }
@@ -657,15 +4942,11 @@ fn try_apply_etc(
     original_content: &Option<Vec<u8>>,
     repo: &Path,
 ) -> Result<Option<String>> {
-    let etc_mapping_path = Path::new("/etc/nix-file-overlay/etc-mapping.json");
-    if !etc_mapping_path.exists() {
+    let Some(mapping) = load_etc_mapping()? else {
         return Ok(None);
-    }
-
-    let data = fs::read_to_string(etc_mapping_path)?;
-    let mapping: HashMap<String, EtcMappingEntry> = serde_json::from_str(&data)?;
+    };
 
-    for (_key, entry) in &mapping {
+    for entry in mapping.values() {
         let Some(p) = &entry.path else { continue };
         if Path::new(p) != abs_path {
             continue;
@@ -678,7 +4959,7 @@ fn try_apply_etc(
 
         if let Some(src) = &entry.source {
             let src_path = Path::new(src);
-            if let Some(repo_rel) = extract_repo_relative(src) {
+            if let StoreOrigin::SelfRepo(repo_rel) = resolve_store_origin(src, repo) {
                 let repo_file = repo.join(&repo_rel);
                 if repo_file.exists() && repo_file.is_file() {
                     fs::write(&repo_file, modified_content)?;
@@ -720,166 +5001,946 @@ fn try_apply_etc(
         )));
     }
 
-    Ok(None)
+    Ok(None)
+}
+
+fn generate_diff(original: &Option<Vec<u8>>, modified: &[u8]) -> String {
+    let orig_str = original
+        .as_ref()
+        .map(|o| String::from_utf8_lossy(o).into_owned())
+        .unwrap_or_else(|| "<original not available>".to_string());
+    let mod_str = String::from_utf8_lossy(modified);
+
+    let orig_lines: Vec<&str> = orig_str.lines().collect();
+    let mod_lines: Vec<&str> = mod_str.lines().collect();
+
+    let mut diff = String::new();
+    let max = orig_lines.len().max(mod_lines.len());
+    for i in 0..max {
+        let orig_line = orig_lines.get(i).copied().unwrap_or("");
+        let mod_line = mod_lines.get(i).copied().unwrap_or("");
+        if orig_line != mod_line {
+            if i < orig_lines.len() {
+                diff.push_str(&format!("- {orig_line}\n"));
+            }
+            if i < mod_lines.len() {
+                diff.push_str(&format!("+ {mod_line}\n"));
+            }
+        }
+    }
+
+    if diff.is_empty() {
+        "(no changes detected)".to_string()
+    } else {
+        diff
+    }
+}
+
+fn apply_with_ai(
+    abs_path: &Path,
+    modified_content: &[u8],
+    original_content: &Option<Vec<u8>>,
+    entry: &OverlayEntry,
+    repo: &Path,
+    config: &Config,
+) -> Result<()> {
+    let diff = generate_diff(original_content, modified_content);
+
+    let mut context_parts = vec![
+        format!("File: {}", abs_path.display()),
+        format!("Repository: {}", repo.display()),
+    ];
+
+    if let Some(ref orig) = entry.original_target {
+        context_parts.push(format!("Original store path: {orig}"));
+    }
+    if let Some(g) = &entry.generation {
+        context_parts.push(format!(
+            "Overlay content taken from {} generation {} ({})",
+            g.profile,
+            g.number,
+            g.source.display()
+        ));
+    }
+    if let Some(ref mk) = entry.mapping_key {
+        context_parts.push(format!("Mapping key: {mk}"));
+    }
+    if let Some(ref mt) = entry.mapping_type {
+        context_parts.push(format!("Mapping type: {mt}"));
+    }
+
+    let prompt = format!(
+        "The Nix-managed file at {} has been modified with a temporary overlay. \
+         The NixOS/home-manager configuration repository is at {}. \
+         {}\
+         Find the relevant NixOS or home-manager configuration that generates or manages \
+         this file and update it to reflect these changes:\n\n{diff}",
+        abs_path.display(),
+        repo.display(),
+        if let Some(ref orig) = entry.original_target {
+            format!("The original file was a symlink to {orig}. ")
+        } else {
+            String::new()
+        },
+    );
+
+    if !run_apply_backend(&prompt, repo, config)? {
+        print_guidance(&context_parts, &diff);
+    }
+
+    Ok(())
+}
+
+/// Hand `prompt` to the custom apply command or opencode. Returns `false`
+/// when no backend is configured and the caller should print guidance.
+fn run_apply_backend(prompt: &str, repo: &Path, config: &Config) -> Result<bool> {
+    let command = std::env::var("NIX_FILE_OVERLAY_CMD")
+        .ok()
+        .or(config.apply.command.clone());
+    match command {
+        Some(cmd) if cmd.is_empty() => Ok(false),
+        Some(cmd) => {
+            eprintln!("Running custom apply command...");
+            let status = Command::new("sh")
+                .args(["-c", &format!("{cmd} \"$1\""), "--", prompt])
+                .status()
+                .with_context(|| format!("Failed to run custom command: {cmd}"))?;
+            if !status.success() {
+                eprintln!("Custom command exited with non-zero status");
+            }
+            Ok(true)
+        }
+        None if command_exists("opencode") => {
+            eprintln!("Invoking opencode to find and update the configuration...");
+            let status = Command::new("opencode")
+                .args(["run", prompt])
+                .current_dir(repo)
+                .status()
+                .context("Failed to run opencode")?;
+            if !status.success() {
+                eprintln!("opencode exited with non-zero status");
+            }
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+fn print_guidance(context: &[String], diff: &str) {
+    eprintln!("\nCould not automatically apply changes. Manual steps needed:\n");
+    for line in context {
+        eprintln!("  {line}");
+    }
+    eprintln!("\nChanges made:");
+    eprintln!("{diff}");
+    eprintln!(
+        "\nFind the NixOS or home-manager option that generates this file \
+         and update it to match the changes above."
+    );
+}
+
+fn command_exists(name: &str) -> bool {
+    Command::new("which")
+        .arg(name)
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false)
+}
+
+fn get_user_repo(config: &Config) -> PathBuf {
+    match std::env::var("NIX_FILE_OVERLAY_USER_REPO") {
+        Ok(repo) => PathBuf::from(repo),
+        Err(_) => match &config.repos.user {
+            Some(repo) => expand_home(repo),
+            None => get_home_dir()
+                .unwrap_or_else(|_| PathBuf::from("/root"))
+                .join(".nixos"),
+        },
+    }
+}
+
+fn get_system_repo(config: &Config, user_repo: &Path) -> PathBuf {
+    match std::env::var("NIX_FILE_OVERLAY_SYSTEM_REPO") {
+        Ok(repo) => PathBuf::from(repo),
+        Err(_) => match &config.repos.system {
+            Some(repo) => expand_home(repo),
+            None => user_repo.to_path_buf(),
+        },
+    }
+}
+
+fn repo_for_path(abs_path: &Path, config: &Config) -> Result<PathBuf> {
+    let user_repo = get_user_repo(config);
+    if abs_path.starts_with(get_home_dir()?) {
+        Ok(user_repo)
+    } else {
+        Ok(get_system_repo(config, &user_repo))
+    }
+}
+
+// ── Doctor command ───────────────────────────────────────────────────
+
+/// Tallies what `doctor` found and repaired.
+#[derive(Default)]
+struct DoctorReport {
+    problems: usize,
+    fixed: usize,
+}
+
+impl DoctorReport {
+    fn ok(&self, message: &str) {
+        println!("[ok]    {message}");
+    }
+
+    fn warn(&self, message: &str) {
+        println!("[warn]  {message}");
+    }
+
+    fn problem(&mut self, message: &str) {
+        self.problems += 1;
+        println!("[error] {message}");
+    }
+
+    fn fixed(&mut self, result: Result<()>, message: &str) {
+        match result {
+            Ok(()) => {
+                self.fixed += 1;
+                println!("[fixed] {message}");
+            }
+            Err(e) => println!("[error] could not fix: {e:#}"),
+        }
+    }
+}
+
+fn cmd_doctor(fix: bool, config: &Config) -> Result<()> {
+    let mut report = DoctorReport::default();
+
+    doctor_environment(&mut report);
+
+    let mounts = MountTable::read()?;
+    let mut registries = Vec::new();
+    for reg_path in known_registry_paths() {
+        if !reg_path.exists() {
+            continue;
+        }
+        match load_registry(&reg_path) {
+            Ok(registry) => registries.push((reg_path, registry)),
+            Err(e) => {
+                report.problem(&format!("{e:#}"));
+                if fix {
+                    let result = quarantine_registry(&reg_path);
+                    report.fixed(result, &format!("moved {} aside", reg_path.display()));
+                }
+            }
+        }
+    }
+
+    for (reg_path, registry) in &mut registries {
+        let before = registry.len();
+        doctor_entries(&mut report, reg_path, registry, &mounts, fix, config);
+        if registry.len() != before {
+            match save_registry(reg_path, registry) {
+                Ok(()) => println!("[fixed] rewrote {}", reg_path.display()),
+                Err(e) => report.problem(&format!("{e:#}")),
+            }
+        }
+    }
+
+    let entries: Vec<(&String, &OverlayEntry)> =
+        registries.iter().flat_map(|(_, r)| r.iter()).collect();
+    let overlay_dirs: Vec<PathBuf> = known_registry_paths()
+        .iter()
+        .filter_map(|r| Some(r.parent()?.join("overlays")))
+        .collect();
+    doctor_leftover_mounts(&mut report, &entries, &overlay_dirs, &mounts, fix);
+    doctor_orphan_copies(&mut report, &entries, &overlay_dirs, &mounts, fix);
+
+    let remaining = report.problems - report.fixed.min(report.problems);
+    if remaining == 0 {
+        println!("\nNo problems left.");
+        return Ok(());
+    }
+    if fix {
+        bail!("{remaining} problem(s) could not be fixed");
+    }
+    bail!("{remaining} problem(s) found; run `nix-file-overlay doctor --fix` to repair");
+}
+
+fn doctor_environment(report: &mut DoctorReport) {
+    if !command_exists("sudo") {
+        report.problem("sudo is not installed; overlays cannot be mounted");
+    } else if is_root()
+        || Command::new("sudo")
+            .args(["-n", "true"])
+            .stderr(std::process::Stdio::null())
+            .status()
+            .is_ok_and(|s| s.success())
+    {
+        report.ok("sudo is usable");
+    } else {
+        report.warn("sudo needs a password; mounting will prompt for it");
+    }
+
+    match get_hm_mapping_path() {
+        Ok(p) if p.exists() => report.ok(&format!("Home-Manager mapping at {}", p.display())),
+        Ok(p) => report.warn(&format!(
+            "no Home-Manager mapping at {}; home files can't be traced to their source",
+            p.display()
+        )),
+        Err(e) => report.warn(&format!("{e:#}")),
+    }
+    if Path::new(ETC_MAPPING_PATH).exists() {
+        report.ok(&format!("/etc mapping at {ETC_MAPPING_PATH}"));
+    } else {
+        report.warn(&format!(
+            "no /etc mapping at {ETC_MAPPING_PATH}; /etc files can't be traced to their source"
+        ));
+    }
+}
+
+/// Check every entry of one registry against the mount table, dropping
+/// dead entries when fixing.
+fn doctor_entries(
+    report: &mut DoctorReport,
+    reg_path: &Path,
+    registry: &mut Registry,
+    mounts: &MountTable,
+    fix: bool,
+    config: &Config,
+) {
+    let mut dead = Vec::new();
+
+    let mut keys: Vec<String> = registry.keys().cloned().collect();
+    keys.sort();
+    for key in keys {
+        let entry = &registry[&key];
+        let path = key_path(&key);
+        let shown = path.display();
+
+        if is_dropin_entry(entry) {
+            if !path.exists() {
+                report.problem(&format!("drop-in {shown} is registered but not installed"));
+                dead.push(key);
+            }
+            continue;
+        }
+
+        if !entry.stored_copy.exists() {
+            report.problem(&format!(
+                "stored copy {} for {shown} is missing",
+                entry.stored_copy.display()
+            ));
+            dead.push(key);
+            continue;
+        }
+
+        match mounts.overlay_state(&path, &entry.stored_copy) {
+            MountState::Ours => {}
+            MountState::Unmounted if entry.persistent => {
+                report.problem(&format!("persistent overlay {shown} is not mounted"));
+                if fix {
+                    let result = check_restore(&path, entry, config).and_then(|()| {
+                        run_sudo_mount(&entry.stored_copy, &path, &mount_owners(entry, reg_path))
+                    });
+                    report.fixed(result, &format!("remounted {shown}"));
+                }
+            }
+            MountState::Unmounted => {
+                report.problem(&format!("temporary overlay {shown} is not mounted"));
+                dead.push(key.clone());
+            }
+            MountState::Foreign => report.problem(&format!(
+                "{shown} is covered by a mount that is not its overlay; unmount it by hand"
+            )),
+            MountState::Stacked { layers, .. } => {
+                report.warn(&format!("{layers} mounts are stacked on {shown}"));
+            }
+        }
+
+        if let Ok(Some(m)) = find_mapping_for_path(&path)
+            && let Some(reason) = mapping_mismatch(&path, &m)
+        {
+            report.warn(&format!(
+                "mapping is out of date with the live generation: {reason}"
+            ));
+        }
+    }
+
+    if !fix {
+        return;
+    }
+    for key in dead {
+        if let Some(entry) = registry.remove(&key) {
+            remove_overlay_files(&entry);
+            report.fixed(
+                Ok(()),
+                &format!("dropped entry for {}", key_path(&key).display()),
+            );
+        }
+    }
+}
+
+/// Mounts of files in our storage that no registry entry accounts for,
+/// e.g. left behind by a crash or a deleted registry.
+fn doctor_leftover_mounts(
+    report: &mut DoctorReport,
+    entries: &[(&String, &OverlayEntry)],
+    overlay_dirs: &[PathBuf],
+    mounts: &MountTable,
+    fix: bool,
+) {
+    for mount in &mounts.mounts {
+        let Some((source, deleted)) = mounts.source_of(mount) else {
+            continue;
+        };
+        if !overlay_dirs.iter().any(|d| source.starts_with(d)) {
+            continue;
+        }
+        let accounted = !deleted
+            && entries.iter().any(|(key, entry)| {
+                let path = key_path(key);
+                mounts
+                    .mounts_at(&path)
+                    .iter()
+                    .any(|m| m.mount_id == mount.mount_id)
+                    && mounts.is_our_mount(mount, &entry.stored_copy)
+            });
+        if accounted {
+            continue;
+        }
+
+        let target = mount.mount_point.display();
+        report.problem(&format!(
+            "{target} has a leftover mount of {}{}",
+            source.display(),
+            if deleted { " (deleted)" } else { "" }
+        ));
+        if fix && confirm(&format!("Unmount {target}?"), false) {
+            let result = run_sudo_umount(&mount.mount_point);
+            report.fixed(result, &format!("unmounted {target}"));
+        }
+    }
+}
+
+/// Stored copies no registry entry refers to.
+fn doctor_orphan_copies(
+    report: &mut DoctorReport,
+    entries: &[(&String, &OverlayEntry)],
+    overlay_dirs: &[PathBuf],
+    mounts: &MountTable,
+    fix: bool,
+) {
+    let mounted: Vec<PathBuf> = mounts
+        .mounts
+        .iter()
+        .filter_map(|m| Some(mounts.source_of(m)?.0))
+        .collect();
+
+    for dir in overlay_dirs {
+        let Ok(read) = fs::read_dir(dir) else {
+            continue;
+        };
+        for item in read.flatten() {
+            let orphan = item.path();
+            let referenced = entries.iter().any(|(_, e)| {
+                e.stored_copy == orphan
+                    || e.stored_copy.parent() == Some(orphan.as_path())
+                    || e.base_copy.as_deref() == Some(orphan.as_path())
+            });
+            if referenced || mounted.iter().any(|m| m.starts_with(&orphan)) {
+                continue;
+            }
+
+            report.problem(&format!("{} belongs to no overlay", orphan.display()));
+            if fix {
+                let result = remove_orphan(&orphan);
+                report.fixed(result, &format!("removed {}", orphan.display()));
+            }
+        }
+    }
 }
 
-fn extract_repo_relative(store_path: &str) -> Option<String> {
-    let idx = store_path.find("-source/")?;
-    let after = &store_path[idx + "-source/".len()..];
-    if after.is_empty() {
-        None
-    } else {
-        Some(after.to_string())
+fn remove_orphan(orphan: &Path) -> Result<()> {
+    if !orphan.is_dir() {
+        return storage_remove(orphan);
+    }
+    for file in fs::read_dir(orphan)?.flatten() {
+        storage_remove(&file.path())?;
     }
+    Ok(())
 }
 
-fn generate_diff(original: &Option<Vec<u8>>, modified: &[u8]) -> String {
-    let orig_str = original
-        .as_ref()
-        .map(|o| String::from_utf8_lossy(o).into_owned())
-        .unwrap_or_else(|| "<original not available>".to_string());
-    let mod_str = String::from_utf8_lossy(modified);
+/// Keep an unreadable registry for inspection and start over with an empty
+/// one.
+fn quarantine_registry(reg_path: &Path) -> Result<()> {
+    let data = fs::read(reg_path)?;
+    let mut aside = reg_path.as_os_str().to_os_string();
+    aside.push(".corrupt");
+    storage_write(Path::new(&aside), &data, false)?;
+    save_registry(reg_path, &Registry::new())
+}
 
-    let orig_lines: Vec<&str> = orig_str.lines().collect();
-    let mod_lines: Vec<&str> = mod_str.lines().collect();
+// ── Audit log ────────────────────────────────────────────────────────
 
-    let mut diff = String::new();
-    let max = orig_lines.len().max(mod_lines.len());
-    for i in 0..max {
-        let orig_line = orig_lines.get(i).copied().unwrap_or("");
-        let mod_line = mod_lines.get(i).copied().unwrap_or("");
-        if orig_line != mod_line {
-            if i < orig_lines.len() {
-                diff.push_str(&format!("- {orig_line}\n"));
-            }
-            if i < mod_lines.len() {
-                diff.push_str(&format!("+ {mod_line}\n"));
-            }
+/// Marks our records in the journal, for `journalctl MESSAGE_ID=...`.
+const AUDIT_MESSAGE_ID: &str = "3b1f6c0e9d2a4f57a8e4c6b2d90f1e73";
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum AuditAction {
+    Create,
+    Edit,
+    Reset,
+    Remove,
+    Apply,
+    Restore,
+}
+
+impl AuditAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuditAction::Create => "create",
+            AuditAction::Edit => "edit",
+            AuditAction::Reset => "reset",
+            AuditAction::Remove => "remove",
+            AuditAction::Apply => "apply",
+            AuditAction::Restore => "restore",
         }
     }
+}
 
-    if diff.is_empty() {
-        "(no changes detected)".to_string()
-    } else {
-        diff
-    }
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuditRecord {
+    time: String,
+    user: String,
+    uid: u32,
+    action: AuditAction,
+    path: String,
+    #[serde(alias = "mapping_key")]
+    mapping_key: Option<String>,
+    /// SHA-256 of the Nix-managed original.
+    #[serde(alias = "base_sha256")]
+    base_sha256: Option<String>,
+    /// SHA-256 of the overlay content the operation left in place, or of the
+    /// content it took down when the overlay is gone.
+    #[serde(alias = "new_sha256")]
+    new_sha256: Option<String>,
+    ok: bool,
+    error: Option<String>,
 }
 
-fn apply_with_ai(
+fn audit_log_path() -> Result<PathBuf> {
+    Ok(get_data_dir()?.join("audit.jsonl"))
+}
+
+/// Changes to system paths are also logged here, where users can't edit them.
+fn system_audit_log_path() -> PathBuf {
+    get_system_data_dir().join("audit.jsonl")
+}
+
+/// Run `op` on the overlay at `path` and record the outcome.
+fn audited(action: AuditAction, path: &Path, op: impl FnOnce() -> Result<()>) -> Result<()> {
+    let abs_path = resolve_path(path)
+        .unwrap_or_else(|_| std::env::current_dir().unwrap_or_default().join(path));
+    let before = find_overlay_entry(&abs_path).ok().map(|(entry, _)| entry);
+    let before_hashes = before.as_ref().map(content_hashes);
+    let result = op();
+    let after = find_overlay_entry(&abs_path).ok().map(|(entry, _)| entry);
+    let entries: Vec<&OverlayEntry> = after.iter().chain(&before).collect();
+
+    let (base, new) = after.as_ref().map(content_hashes).unwrap_or_default();
+    let (base_before, new_before) = before_hashes.unwrap_or_default();
+    write_audit(
+        action,
+        &abs_path,
+        &entries,
+        base.or(base_before),
+        new.or(new_before),
+        &result,
+    );
+    result
+}
+
+/// Record an operation on an overlay whose entry stays in place.
+fn audit(action: AuditAction, abs_path: &Path, entries: &[&OverlayEntry], result: &Result<()>) {
+    let (base, new) = entries
+        .first()
+        .map(|e| content_hashes(e))
+        .unwrap_or_default();
+    write_audit(action, abs_path, entries, base, new, result);
+}
+
+/// Hashes of an overlay's original and current content.
+fn content_hashes(entry: &OverlayEntry) -> (Option<String>, Option<String>) {
+    let hash = |data: Vec<u8>| format!("{:x}", Sha256::digest(data));
+    (
+        read_original_content(entry).map(hash),
+        fs::read(&entry.stored_copy).ok().map(hash),
+    )
+}
+
+/// Append a record to the audit log and send it to the journal. Neither may
+/// fail the operation it records.
+fn write_audit(
+    action: AuditAction,
     abs_path: &Path,
-    modified_content: &[u8],
-    original_content: &Option<Vec<u8>>,
-    entry: &OverlayEntry,
-    repo: &Path,
-) -> Result<()> {
-    let diff = generate_diff(original_content, modified_content);
+    entries: &[&OverlayEntry],
+    base_sha256: Option<String>,
+    new_sha256: Option<String>,
+    result: &Result<()>,
+) {
+    let uid = invoking_uid();
+    let record = AuditRecord {
+        time: Utc::now().to_rfc3339(),
+        user: user_name(uid),
+        uid,
+        action,
+        path: path_key(abs_path),
+        mapping_key: entries.iter().find_map(|e| e.mapping_key.clone()),
+        base_sha256,
+        new_sha256,
+        ok: result.is_ok(),
+        error: result.as_ref().err().map(|e| format!("{e:#}")),
+    };
+    if let Err(e) = audit_log_path().and_then(|path| append_record(&path, &record, 0o600)) {
+        eprintln!("Warning: failed to write the audit log: {e:#}");
+    }
+    // The helper only accepts records for paths overlays can cover.
+    let system_path = !is_user_path(abs_path).unwrap_or(false)
+        && (abs_path.is_symlink() || is_system_dropin(abs_path));
+    if system_path && let Err(e) = append_system_audit(&record) {
+        eprintln!("Warning: failed to write the system audit log: {e:#}");
+    }
+    send_to_journal(&record);
+}
 
-    let mut context_parts = vec![
-        format!("File: {}", abs_path.display()),
-        format!("Repository: {}", repo.display()),
-    ];
+fn append_system_audit(record: &AuditRecord) -> Result<()> {
+    let path = system_audit_log_path();
+    if is_root() {
+        return append_record(&path, record, 0o644);
+    }
+    run_helper(
+        "append-audit",
+        &[],
+        &path,
+        Some(&serde_json::to_vec(record)?),
+    )
+}
 
-    if let Some(ref orig) = entry.original_target {
-        context_parts.push(format!("Original store path: {orig}"));
+/// Check a record a user asks the helper to add to the system audit log
+/// against what root can see. The path must be one the helper would mount
+/// over or install a drop-in at, and whether it is overlaid now must match
+/// what the action claims. Everything else is taken from the live file and
+/// the system registry instead of the user.
+fn verify_audit_record(record: &mut AuditRecord) -> Result<()> {
+    let abs_path = key_path(&record.path);
+    let dropin = is_system_dropin(&abs_path);
+    let nix_managed = abs_path.is_symlink()
+        && fs::canonicalize(&abs_path).is_ok_and(|t| t.starts_with("/nix/store"));
+    if !is_plain_path(&abs_path) || !(dropin || nix_managed) {
+        bail!(
+            "Refusing to log {}: not a path overlays can cover",
+            abs_path.display()
+        );
     }
-    if let Some(ref mk) = entry.mapping_key {
-        context_parts.push(format!("Mapping key: {mk}"));
+
+    let live = if dropin {
+        abs_path.exists()
+    } else {
+        is_bind_mounted(&abs_path)?
+    };
+    let consistent = match record.action {
+        _ if !record.ok => true,
+        AuditAction::Remove => !live,
+        AuditAction::Create | AuditAction::Edit | AuditAction::Reset | AuditAction::Restore => live,
+        // Apply leaves the overlay in place when it can't write the source.
+        AuditAction::Apply => true,
+    };
+    if !consistent {
+        bail!(
+            "Refusing to log {} of {}: the path is {}overlaid",
+            record.action.as_str(),
+            abs_path.display(),
+            if live { "" } else { "not " }
+        );
     }
-    if let Some(ref mt) = entry.mapping_type {
-        context_parts.push(format!("Mapping type: {mt}"));
+
+    let hash = |data: Vec<u8>| format!("{:x}", Sha256::digest(data));
+    let system_entry = load_registry(&get_system_data_dir().join("registry.json"))
+        .ok()
+        .and_then(|mut registry| registry.remove(&record.path));
+    record.mapping_key = system_entry.as_ref().and_then(|e| e.mapping_key.clone());
+    // Read through the mount, the path shows the overlay; without one, the
+    // original. Content that was taken down can't be checked any more.
+    record.new_sha256 = live.then(|| fs::read(&abs_path).ok().map(hash)).flatten();
+    record.base_sha256 = match &system_entry {
+        Some(entry) => read_original_content(entry).map(hash),
+        None if !live && !dropin => fs::read(&abs_path).ok().map(hash),
+        None => None,
+    };
+    Ok(())
+}
+
+fn append_record(path: &Path, record: &AuditRecord, mode: u32) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
     }
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(mode)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .and_then(|mut f| f.write_all(&line))
+        .with_context(|| format!("Failed to append to {}", path.display()))
+}
 
-    let prompt = format!(
-        "The Nix-managed file at {} has been modified with a temporary overlay. \
-         The NixOS/home-manager configuration repository is at {}. \
-         {}\
-         Find the relevant NixOS or home-manager configuration that generates or manages \
-         this file and update it to reflect these changes:\n\n{diff}",
-        abs_path.display(),
-        repo.display(),
-        if let Some(ref orig) = entry.original_target {
-            format!("The original file was a symlink to {orig}. ")
-        } else {
-            String::new()
-        },
+/// Log a record through journald's native protocol, so each field can be
+/// matched on with journalctl.
+fn send_to_journal(record: &AuditRecord) {
+    let message = format!(
+        "{} {} {}: {}",
+        record.user,
+        record.action.as_str(),
+        record.path,
+        record.error.as_deref().unwrap_or("ok")
     );
+    let optional = |value: &Option<String>| value.clone().unwrap_or_default();
+    let fields = [
+        ("MESSAGE", message),
+        ("MESSAGE_ID", AUDIT_MESSAGE_ID.to_string()),
+        ("PRIORITY", if record.ok { "6" } else { "4" }.to_string()),
+        ("SYSLOG_IDENTIFIER", "nix-file-overlay".to_string()),
+        ("OVERLAY_ACTION", record.action.as_str().to_string()),
+        ("OVERLAY_PATH", record.path.clone()),
+        ("OVERLAY_USER", record.user.clone()),
+        ("OVERLAY_MAPPING_KEY", optional(&record.mapping_key)),
+        ("OVERLAY_BASE_SHA256", optional(&record.base_sha256)),
+        ("OVERLAY_NEW_SHA256", optional(&record.new_sha256)),
+        (
+            "OVERLAY_RESULT",
+            if record.ok { "ok" } else { "failed" }.to_string(),
+        ),
+        ("OVERLAY_ERROR", optional(&record.error)),
+    ];
+    let mut datagram = Vec::new();
+    for (name, value) in fields.iter().filter(|(_, v)| !v.is_empty()) {
+        datagram.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            // Multi-line values are sent length-prefixed.
+            datagram.push(b'\n');
+            datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            datagram.push(b'=');
+        }
+        datagram.extend_from_slice(value.as_bytes());
+        datagram.push(b'\n');
+    }
+    if let Ok(socket) = std::os::unix::net::UnixDatagram::unbound() {
+        socket.send_to(&datagram, JOURNAL_SOCKET).ok();
+    }
+}
 
-    let cmd_var = std::env::var("NIX_FILE_OVERLAY_CMD");
+// ── Log command ──────────────────────────────────────────────────────
 
-    match cmd_var {
-        Ok(cmd) if cmd.is_empty() => {
-            print_guidance(&context_parts, &diff);
-        }
-        Ok(cmd) => {
-            eprintln!("Running custom apply command...");
-            let status = Command::new("sh")
-                .args(["-c", &format!("{cmd} \"$1\""), "--", &prompt])
-                .status()
-                .with_context(|| format!("Failed to run custom command: {cmd}"))?;
-            if !status.success() {
-                eprintln!("Custom command exited with non-zero status");
+fn cmd_log(
+    path: Option<&Path>,
+    since: Option<chrono::Duration>,
+    action: Option<AuditAction>,
+    failed: bool,
+    json: bool,
+) -> Result<()> {
+    let mut data = String::new();
+    for log_path in [audit_log_path()?, system_audit_log_path()] {
+        match fs::read_to_string(&log_path) {
+            Ok(log) => data.push_str(&log),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", log_path.display()));
             }
         }
-        Err(_) => {
-            if command_exists("opencode") {
-                eprintln!("Invoking opencode to find and update the configuration...");
-                let status = Command::new("opencode")
-                    .args(["run", &prompt])
-                    .current_dir(repo)
-                    .status()
-                    .context("Failed to run opencode")?;
-                if !status.success() {
-                    eprintln!("opencode exited with non-zero status");
-                }
-            } else {
-                print_guidance(&context_parts, &diff);
-            }
+    }
+    let prefix = path.map(std::path::absolute).transpose()?;
+    let cutoff = since
+        .map(|since| {
+            Utc::now()
+                .checked_sub_signed(since)
+                .context("--since is too long")
+        })
+        .transpose()?;
+
+    let mut records: Vec<AuditRecord> = data
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .filter(|r: &AuditRecord| {
+            prefix
+                .as_ref()
+                .is_none_or(|p| key_path(&r.path).starts_with(p))
+                && cutoff.is_none_or(|cutoff| {
+                    chrono::DateTime::parse_from_rfc3339(&r.time).is_ok_and(|t| t >= cutoff)
+                })
+                && action.is_none_or(|a| r.action == a)
+                && (!failed || !r.ok)
+        })
+        .collect();
+    // Our own changes to system paths are in both logs.
+    records.sort_by(|a, b| a.time.cmp(&b.time));
+    records.dedup_by(|a, b| {
+        (&a.time, a.uid, &a.path, a.action) == (&b.time, b.uid, &b.path, b.action)
+    });
+
+    if json {
+        for record in &records {
+            println!("{}", serde_json::to_string(record)?);
         }
+        return Ok(());
+    }
+
+    if records.is_empty() {
+        eprintln!("No matching audit records.");
+        return Ok(());
+    }
+
+    println!(
+        "{:<16} {:<12} {:<8} {:<7} {:<14} PATH",
+        "TIME", "USER", "ACTION", "RESULT", "CONTENT"
+    );
+    println!("{}", "-".repeat(100));
+    for record in &records {
+        let short = |hash: &Option<String>| {
+            hash.as_deref()
+                .map(|h| h.get(..6).unwrap_or(h).to_string())
+                .unwrap_or_default()
+        };
+        let content = match (&record.base_sha256, &record.new_sha256) {
+            (None, None) => "-".to_string(),
+            (base, new) => format!("{}..{}", short(base), short(new)),
+        };
+        println!(
+            "{:<16} {:<12} {:<8} {:<7} {:<14} {}{}",
+            short_time(&record.time),
+            record.user,
+            record.action.as_str(),
+            if record.ok { "ok" } else { "failed" },
+            content,
+            key_path(&record.path).display(),
+            record
+                .error
+                .as_ref()
+                .map(|e| format!(" ({e})"))
+                .unwrap_or_default()
+        );
     }
 
     Ok(())
 }
 
-fn print_guidance(context: &[String], diff: &str) {
-    eprintln!("\nCould not automatically apply changes. Manual steps needed:\n");
-    for line in context {
-        eprintln!("  {line}");
+// ── Expire command ───────────────────────────────────────────────────
+
+/// Parse a duration such as `90s`, `30m`, `2h`, `1d` or `1h30m`.
+fn parse_duration(s: &str) -> Result<chrono::Duration, String> {
+    let mut total = chrono::Duration::zero();
+    let mut digits = String::new();
+    for c in s.trim().chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let n: i64 = digits
+            .parse()
+            .map_err(|_| format!("invalid duration '{s}'"))?;
+        digits.clear();
+        let part = match c {
+            's' => chrono::Duration::try_seconds(n),
+            'm' => chrono::Duration::try_minutes(n),
+            'h' => chrono::Duration::try_hours(n),
+            'd' => chrono::Duration::try_days(n),
+            'w' => chrono::Duration::try_weeks(n),
+            _ => {
+                return Err(format!(
+                    "invalid duration unit '{c}' in '{s}' (use s, m, h, d or w)"
+                ));
+            }
+        };
+        total = part
+            .and_then(|part| total.checked_add(&part))
+            .ok_or_else(|| format!("duration '{s}' is too long"))?;
     }
-    eprintln!("\nChanges made:");
-    eprintln!("{diff}");
-    eprintln!(
-        "\nFind the NixOS or home-manager option that generates this file \
-         and update it to match the changes above."
-    );
+    if !digits.is_empty() || total <= chrono::Duration::zero() {
+        return Err(format!("invalid duration '{s}' (e.g. 30m, 2h, 1d)"));
+    }
+    Ok(total)
 }
 
-fn command_exists(name: &str) -> bool {
-    Command::new("which")
-        .arg(name)
-        .output()
-        .map(|o| o.status.success())
-        .unwrap_or(false)
+fn is_expired(entry: &OverlayEntry, logout: bool) -> bool {
+    // The runtime dir is wiped after logout, which would leave mounts
+    // without a source behind.
+    let in_runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .is_some_and(|runtime| entry.stored_copy.starts_with(runtime));
+    if logout && (entry.until_logout || in_runtime_dir) {
+        return true;
+    }
+    entry
+        .expires_at
+        .as_deref()
+        .and_then(|e| chrono::DateTime::parse_from_rfc3339(e).ok())
+        .is_some_and(|e| e <= Utc::now())
 }
 
-fn get_user_repo() -> Result<PathBuf> {
-    Ok(std::env::var("NIX_FILE_OVERLAY_USER_REPO")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| {
-            get_home_dir()
-                .unwrap_or_else(|_| PathBuf::from("/root"))
-                .join(".nixos")
-        }))
+/// Revert overlays whose time-to-live ran out (and, at logout, the
+/// session-scoped ones), notifying the user about each.
+fn cmd_expire(logout: bool, config: &Config) -> Result<()> {
+    for path in prune_dead_temp_entries() {
+        eprintln!("Pruned temporary overlay from a previous boot: {path}");
+    }
+
+    for (path_str, entry, _) in load_all_overlays() {
+        if !is_expired(&entry, logout) {
+            continue;
+        }
+        let abs_path = key_path(&path_str);
+        let Ok((_, reg_path)) = find_overlay_entry(&abs_path) else {
+            continue;
+        };
+
+        match audited(AuditAction::Remove, &abs_path, || {
+            cmd_remove(&abs_path, Some(&reg_path), config)
+        }) {
+            Ok(()) => notify(&format!("Reverted expired overlay: {path_str}")),
+            Err(e) => eprintln!("Warning: failed to revert expired overlay {path_str}: {e}"),
+        }
+    }
+    Ok(())
 }
 
-fn get_system_repo(user_repo: &Path) -> PathBuf {
-    std::env::var("NIX_FILE_OVERLAY_SYSTEM_REPO")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| user_repo.to_path_buf())
+/// Show a desktop notification, when a notification daemon is reachable.
+fn notify(message: &str) {
+    eprintln!("{message}");
+    if command_exists("notify-send") {
+        Command::new("notify-send")
+            .args(["--app-name=nix-file-overlay", "nix-file-overlay", message])
+            .status()
+            .ok();
+    }
 }
 
 // ── Restore command ──────────────────────────────────────────────────
 
-fn cmd_restore(registry_path: Option<&Path>) -> Result<()> {
+/// Checks a stored copy has to pass before it is mounted again: validators,
+/// pre-hooks and any policy that has since come to deny the path.
+fn check_restore(path: &Path, entry: &OverlayEntry, config: &Config) -> Result<()> {
+    if let Some(reason) = policy_for(path, config)?.deny {
+        bail!("Policy forbids changing {} ({reason})", path.display());
+    }
+    check_change(path, &entry.stored_copy, HookEvent::Restore, config)
+}
+
+fn cmd_restore(registry_path: Option<&Path>, config: &Config) -> Result<()> {
     let registries: Vec<PathBuf> = if let Some(p) = registry_path {
         vec![p.to_path_buf()]
     } else {
@@ -891,6 +5952,7 @@ fn cmd_restore(registry_path: Option<&Path>) -> Result<()> {
         paths
     };
 
+    let mounts = MountTable::read()?;
     let mut restored = 0;
     let mut stale = 0;
 
@@ -907,7 +5969,7 @@ fn cmd_restore(registry_path: Option<&Path>) -> Result<()> {
                 continue;
             }
 
-            let path = Path::new(path_str);
+            let path = &key_path(path_str);
 
             if !entry.stored_copy.exists() {
                 eprintln!(
@@ -919,7 +5981,26 @@ fn cmd_restore(registry_path: Option<&Path>) -> Result<()> {
                 continue;
             }
 
-            if is_bind_mounted(path).unwrap_or(false) {
+            match mounts.overlay_state(path, &entry.stored_copy) {
+                MountState::Unmounted => {}
+                MountState::Foreign => {
+                    eprintln!(
+                        "Warning: not restoring {}: something else is mounted there",
+                        path_str
+                    );
+                    continue;
+                }
+                MountState::Ours | MountState::Stacked { .. } => continue,
+            }
+
+            // Only the user's expire timer would revert it again, and that
+            // never runs on a machine nobody logs into.
+            if is_expired(entry, false) {
+                eprintln!("Not restoring expired overlay {}", path_str);
+                remove_overlay_files(entry);
+                audit(AuditAction::Remove, path, &[entry], &Ok(()));
+                to_remove.push(path_str.clone());
+                stale += 1;
                 continue;
             }
 
@@ -933,10 +6014,35 @@ fn cmd_restore(registry_path: Option<&Path>) -> Result<()> {
                 continue;
             }
 
-            match run_sudo_mount(&entry.stored_copy, path) {
-                Ok(()) => restored += 1,
+            if let Err(e) = check_restore(path, entry, config) {
+                eprintln!("Warning: not restoring {}: {e}", path_str);
+                audit(AuditAction::Restore, path, &[entry], &Err(e));
+                continue;
+            }
+
+            if let Some(attrs) = entry_attrs(entry) {
+                let current = fs::symlink_metadata(&entry.stored_copy).map(|m| file_attrs(&m));
+                if current.ok() != Some(attrs) {
+                    eprintln!(
+                        "Warning: mode or owner of the stored copy for {} changed, resetting to {:o} {}:{}",
+                        path_str, attrs.mode, attrs.uid, attrs.gid
+                    );
+                    if let Err(e) = storage_set_attrs(&entry.stored_copy, attrs) {
+                        eprintln!("Warning: not restoring {}: {e}", path_str);
+                        continue;
+                    }
+                }
+            }
+
+            let mounted = run_sudo_mount(&entry.stored_copy, path, &mount_owners(entry, reg_path));
+            match &mounted {
+                Ok(()) => {
+                    restored += 1;
+                    run_post_hooks(path, HookEvent::Restore, HookMode::Always, config).ok();
+                }
                 Err(e) => eprintln!("Warning: failed to restore {}: {e}", path_str),
             }
+            audit(AuditAction::Restore, path, &[entry], &mounted);
         }
 
         for key in &to_remove {
@@ -954,10 +6060,437 @@ fn cmd_restore(registry_path: Option<&Path>) -> Result<()> {
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scratch directory that is removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!(
+                "nix-file-overlay-test-{name}-{}",
+                std::process::id()
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    #[test]
+    fn nix_base32_matches_nix() {
+        // `nix hash convert --to nix32` of the SHA-256 of the empty string.
+        assert_eq!(
+            nix_base32(&Sha256::digest(b"")),
+            "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+        );
+        assert_eq!(nix_base32(&[0; 20]), "0".repeat(32));
+    }
+
+    #[test]
+    fn longest_embedded_run_counts_distinctive_lines() {
+        let original = [
+            "server_name example.org;",
+            "}",
+            "root /var/www/html;",
+            "index index.html;",
+        ];
+        let nix = [
+            "{ pkgs, ... }:",
+            "''",
+            "server_name example.org;",
+            "}",
+            "root /var/www/html;",
+            "index index.html;",
+            "''",
+        ];
+        assert_eq!(longest_embedded_run(&original, &nix), Some((3, 3)));
+
+        // Of two equally long runs, the one ending first in the original wins.
+        let nix = [
+            "root /var/www/html;",
+            "index index.html;",
+            "# later",
+            "server_name example.org;",
+            "}",
+            "root /var/www/html;",
+        ];
+        assert_eq!(longest_embedded_run(&original, &nix), Some((4, 2)));
+
+        // Braces and blank lines alone are no match.
+        assert_eq!(longest_embedded_run(&original, &["}", ""]), None);
+        assert_eq!(longest_embedded_run(&original, &["listen 80;"]), None);
+    }
+
+    #[test]
+    fn locate_sources_ranks_candidates() {
+        let repo = TempDir::new("locator");
+        let original = "server_name example.org;\n\
+                        root /var/www/html;\n\
+                        index index.html;\n\
+                        access_log /var/log/nginx/access.log;\n";
+        let embed = |lines: &[&str]| format!("{{\n  text = ''\n{}\n  '';\n}}\n", lines.join("\n"));
+        let lines: Vec<&str> = original.lines().collect();
+
+        fs::write(repo.0.join("nginx.conf"), format!("{original}\n\n")).unwrap();
+        // Equal runs are ordered by path.
+        fs::write(repo.0.join("b.nix"), embed(&lines[..3])).unwrap();
+        fs::write(repo.0.join("a.nix"), embed(&lines[1..])).unwrap();
+        fs::write(repo.0.join("all.nix"), embed(&lines)).unwrap();
+        // Too short a run to count.
+        fs::write(repo.0.join("short.nix"), embed(&lines[..2])).unwrap();
+        // Embedded copies only count in Nix files.
+        fs::write(repo.0.join("notes.md"), embed(&lines)).unwrap();
+        // A verbatim copy over the size limit is not read.
+        let padding = "\n".repeat(LOCATOR_MAX_FILE_SIZE as usize);
+        fs::write(repo.0.join("big.conf"), format!("{original}{padding}")).unwrap();
+
+        let found: Vec<(String, usize)> = locate_sources(&repo.0, original.as_bytes())
+            .into_iter()
+            .map(|c| {
+                let name = c.path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, c.score)
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("nginx.conf".to_string(), usize::MAX),
+                ("all.nix".to_string(), 4),
+                ("a.nix".to_string(), 3),
+                ("b.nix".to_string(), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn dropin_to_nix_renders_assignments() {
+        let dropin = "\
+[Service]
+# comment
+ExecStart=
+ExecStart=/bin/nginx -g 'daemon off;'
+Environment=A=1
+Environment=B=\"2\"
+
+[Install]
+WantedBy=multi-user.target
+";
+        assert_eq!(
+            dropin_to_nix("nginx.service", false, dropin),
+            "systemd.services.nginx.serviceConfig.ExecStart = \
+             lib.mkForce \"/bin/nginx -g 'daemon off;'\";\n\
+             systemd.services.nginx.serviceConfig.Environment = [ \"A=1\" \"B=\\\"2\\\"\" ];\n\
+             # [Install] has no effect in drop-ins; use wantedBy instead\n"
+        );
+
+        assert_eq!(
+            dropin_to_nix(
+                "backup@.timer",
+                true,
+                "[Timer]\nOnCalendar=daily\n[Service]\nNice=5\n"
+            ),
+            "systemd.user.timers.\"backup@\".timerConfig.OnCalendar = \"daily\";\n\
+             # [Service] does not apply to a timer unit\n"
+        );
+    }
+
+    #[test]
+    fn check_kdl_balance_finds_unbalanced_input() {
+        let ok = r##"
+            // comment with a stray {
+            input { keyboard { xkb { layout "us,de"; }; }; }
+            /* nested /* block */ comment } */
+            spawn-at-startup "sh" "-c" "echo \"}\""
+            window-rule { match app-id=r#"^firefox$"#; }
+        "##;
+        assert_eq!(check_kdl_balance(ok), Ok(()));
+
+        assert_eq!(
+            check_kdl_balance("input {\n  keyboard {\n}\n"),
+            Err("'{' on line 1 is never closed".to_string())
+        );
+        assert_eq!(
+            check_kdl_balance("a\n}\n"),
+            Err("unexpected '}' on line 2".to_string())
+        );
+        assert_eq!(
+            check_kdl_balance("a \"b\nc"),
+            Err("unterminated string from line 1".to_string())
+        );
+        assert_eq!(
+            check_kdl_balance("a /* b"),
+            Err("unterminated comment from line 1".to_string())
+        );
+        assert_eq!(
+            check_kdl_balance("a r#\"b\""),
+            Err("unterminated raw string from line 1".to_string())
+        );
+    }
+
+    #[test]
+    fn unescape_mount_path_decodes_octal_escapes() {
+        assert_eq!(
+            unescape_mount_path(br"/home/me/My\040Files/a\011b"),
+            PathBuf::from("/home/me/My Files/a\tb")
+        );
+        assert_eq!(
+            unescape_mount_path(br"/back\134slash"),
+            PathBuf::from("/back\\slash")
+        );
+        // Not an escape: left as is.
+        assert_eq!(
+            unescape_mount_path(br"/a\09x/b\04"),
+            PathBuf::from(r"/a\09x/b\04")
+        );
+        assert_eq!(
+            unescape_mount_path(br"/caf\303\251"),
+            PathBuf::from("/caf\u{e9}")
+        );
+    }
+
+    #[test]
+    fn diff_stat_counts_added_and_removed_lines() {
+        assert_eq!(diff_stat(b"a\nb\nc\n", b"a\nb\nc\n"), (0, 0));
+        assert_eq!(diff_stat(b"a\nb\nc\n", b"a\nB\nc\nd\n"), (2, 1));
+        assert_eq!(diff_stat(b"", b"x\ny\n"), (2, 0));
+        assert_eq!(diff_stat(b"x\ny\n", b""), (0, 2));
+    }
+
+    #[test]
+    fn unit_file_reference_reads_trigger_files() {
+        let dir = TempDir::new("triggers");
+        let restart = dir.0.join("0c2v8x-X-Restart-Triggers-sshd");
+        fs::write(
+            &restart,
+            "/nix/store/a1b2c3-sshd.conf-final /nix/store/d4e5f6-moduli",
+        )
+        .unwrap();
+        let reload = dir.0.join("9k3m1p-X-Reload-Triggers-nginx");
+        fs::write(&reload, "/nix/store/g7h8i9-nginx.conf").unwrap();
+
+        // As generated by NixOS for services.openssh.
+        let sshd = format!(
+            "[Unit]\n\
+             Description=SSH Daemon\n\
+             X-Restart-Triggers={}\n\
+             \n\
+             [Service]\n\
+             ExecStart=/nix/store/j0k1l2-openssh-9.9p1/bin/sshd -D -f /etc/ssh/sshd_config\n\
+             KillMode=process\n",
+            restart.display()
+        );
+        let nginx = format!(
+            "[Unit]\n\
+             X-Reload-Triggers={}\n\
+             [Service]\n\
+             ExecStart=/nix/store/m3n4o5-nginx-1.26.2/bin/nginx -c /etc/nginx/nginx.conf\n",
+            reload.display()
+        );
+        let matching = |needle: &'static str| move |value: &str| mentions_path(value, needle);
+
+        assert_eq!(
+            unit_file_reference(&sshd, &matching("/nix/store/a1b2c3-sshd.conf-final")),
+            Some(false)
+        );
+        assert_eq!(
+            unit_file_reference(&sshd, &matching("/etc/ssh/sshd_config")),
+            Some(false)
+        );
+        assert_eq!(
+            unit_file_reference(&nginx, &matching("/nix/store/g7h8i9-nginx.conf")),
+            Some(true)
+        );
+        assert_eq!(
+            unit_file_reference(&nginx, &matching("/etc/nginx/nginx.conf")),
+            Some(false)
+        );
+        assert_eq!(
+            unit_file_reference(&sshd, &matching("/nix/store/a1b2c3-sshd.conf")),
+            None
+        );
+    }
+
+    #[test]
+    fn collect_files_below_survives_symlink_loops() {
+        let dir = TempDir::new("loop");
+        fs::create_dir(dir.0.join("nvim")).unwrap();
+        fs::write(dir.0.join("nvim/init.lua"), "").unwrap();
+        std::os::unix::fs::symlink(&dir.0, dir.0.join("nvim/up")).unwrap();
+
+        let mut files = Vec::new();
+        collect_files_below(&dir.0, &mut files);
+        assert_eq!(files, vec![dir.0.join("nvim/init.lua")]);
+    }
+
+    #[test]
+    fn resolve_policy_follows_every_spelling_of_a_path() {
+        // A NixOS-style /etc: etc/ssh/sshd_config -> static/ssh/sshd_config
+        // -> the store file.
+        let dir = TempDir::new("policy");
+        let root = &dir.0;
+        for d in ["store", "static/ssh", "etc/ssh"] {
+            fs::create_dir_all(root.join(d)).unwrap();
+        }
+        fs::write(root.join("store/abc-sshd_config"), "PermitRootLogin no\n").unwrap();
+        std::os::unix::fs::symlink(
+            root.join("store/abc-sshd_config"),
+            root.join("static/ssh/sshd_config"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(
+            "../../static/ssh/sshd_config",
+            root.join("etc/ssh/sshd_config"),
+        )
+        .unwrap();
+        fs::write(root.join("etc/ssh/moduli"), "").unwrap();
+
+        let rule = |path: String, action: PolicyAction, reason: &str| PolicyConfig {
+            path,
+            action,
+            max_ttl: Some("1h".to_string()),
+            reason: Some(reason.to_string()),
+        };
+        let rules = [
+            rule(
+                format!("{}/etc/ssh/sshd_config", root.display()),
+                PolicyAction::Deny,
+                "deny",
+            ),
+            rule(
+                format!("{}/etc/ssh/*", root.display()),
+                PolicyAction::Try,
+                "try",
+            ),
+        ];
+        let spellings = [
+            root.join("etc/ssh/sshd_config"),
+            resolve_path(&root.join("etc/./ssh/sshd_config")).unwrap(),
+            resolve_path(&root.join("etc/ssh/../ssh/sshd_config")).unwrap(),
+            root.join("static/ssh/sshd_config"),
+            root.join("store/abc-sshd_config"),
+        ];
+        for path in &spellings {
+            let policy = resolve_policy(&rules, path).unwrap();
+            assert_eq!(policy.deny.as_deref(), Some("deny"), "{}", path.display());
+            assert!(policy.force_try, "{}", path.display());
+        }
+
+        let moduli = resolve_policy(&rules, &root.join("etc/ssh/moduli")).unwrap();
+        assert!(moduli.deny.is_none() && moduli.force_try);
+        let other = resolve_policy(&rules, &root.join("static")).unwrap();
+        assert!(other.deny.is_none() && !other.force_try);
+
+        // The shortest max-ttl wins and every confirm reason is kept.
+        let rules = [
+            rule("/etc/**".to_string(), PolicyAction::MaxTtl, "ttl"),
+            PolicyConfig {
+                max_ttl: Some("10m".to_string()),
+                ..rule("/etc/ssh/*".to_string(), PolicyAction::MaxTtl, "ttl")
+            },
+            rule("/etc/ssh/*".to_string(), PolicyAction::Confirm, "confirm"),
+            rule("/etc/**".to_string(), PolicyAction::Confirm, "confirm"),
+        ];
+        let policy = resolve_policy(&rules, Path::new("/etc/ssh/ssh_config")).unwrap();
+        assert_eq!(
+            policy.max_ttl,
+            Some((chrono::Duration::minutes(10), "10m".to_string()))
+        );
+        assert_eq!(policy.confirm, ["confirm", "confirm"]);
+
+        // A mistyped pattern is an error, not a rule that never matches.
+        let rules = [rule("/etc/[ssh".to_string(), PolicyAction::Deny, "deny")];
+        assert!(resolve_policy(&rules, Path::new("/etc/ssh/sshd_config")).is_err());
+
+        assert!(is_plain_path(Path::new("/etc/ssh/sshd_config")));
+        for path in [
+            "/etc/./ssh/sshd_config",
+            "/etc//ssh/sshd_config",
+            "/etc/ssh/../passwd",
+        ] {
+            assert!(!is_plain_path(Path::new(path)), "{path}");
+        }
+    }
+
+    #[test]
+    fn root_rewrites_user_registry_as_its_owner() {
+        // What the root-run revert of a try does to the user's registry.
+        if !is_root() {
+            return;
+        }
+        let dir = TempDir::new("revert");
+        std::os::unix::fs::chown(&dir.0, Some(4242), Some(4343)).unwrap();
+        let reg_path = dir.0.join("registry.json");
+        fs::write(&reg_path, "{}").unwrap();
+        std::os::unix::fs::chown(&reg_path, Some(4242), Some(4343)).unwrap();
+
+        save_registry(&reg_path, &Registry::new()).unwrap();
+
+        let meta = fs::metadata(&reg_path).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (4242, 4343));
+        assert_eq!(meta.mode() & 0o777, 0o600);
+        assert!(!dir.0.join("registry.json.tmp").exists());
+    }
+
+    #[test]
+    fn parse_duration_accepts_units_and_sums() {
+        assert_eq!(parse_duration("90s"), Ok(chrono::Duration::seconds(90)));
+        assert_eq!(parse_duration(" 2h "), Ok(chrono::Duration::hours(2)));
+        assert_eq!(parse_duration("1h30m"), Ok(chrono::Duration::minutes(90)));
+        assert_eq!(parse_duration("1w1d"), Ok(chrono::Duration::days(8)));
+        for bad in [
+            "",
+            "30",
+            "m",
+            "5x",
+            "0s",
+            "1h-5m",
+            "99999999999999d",
+            "9223372036854775807s",
+        ] {
+            assert!(parse_duration(bad).is_err(), "{bad:?} was accepted");
+        }
+    }
+}
+
 // ── libc binding ─────────────────────────────────────────────────────
 
 mod libc {
+    #[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
+    pub const O_NOFOLLOW: i32 = 0o100000;
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "arm")))]
+    pub const O_NOFOLLOW: i32 = 0o400000;
+
+    pub const IN_NONBLOCK: i32 = 0o4000;
+    pub const IN_CLOEXEC: i32 = 0o2000000;
+    pub const IN_CLOSE_WRITE: u32 = 0x008;
+    pub const IN_MOVED_TO: u32 = 0x080;
+    pub const IN_CREATE: u32 = 0x100;
+    pub const IN_DELETE: u32 = 0x200;
+
+    pub const POLLIN: i16 = 0x001;
+    pub const POLLPRI: i16 = 0x002;
+    pub const POLLERR: i16 = 0x008;
+
+    #[repr(C)]
+    pub struct PollFd {
+        pub fd: i32,
+        pub events: i16,
+        pub revents: i16,
+    }
+
     unsafe extern "C" {
         pub unsafe fn getuid() -> u32;
+        pub unsafe fn getgid() -> u32;
+        pub unsafe fn inotify_init1(flags: i32) -> i32;
+        pub unsafe fn inotify_add_watch(fd: i32, path: *const std::ffi::c_char, mask: u32) -> i32;
+        pub unsafe fn poll(fds: *mut PollFd, nfds: std::ffi::c_ulong, timeout: i32) -> i32;
     }
 }